@@ -1,21 +1,28 @@
 use std::cmp::Ordering;
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::error;
 use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{copy, BufRead};
-use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::io;
+use std::io::copy;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use directories::ProjectDirs;
-use glob::glob;
-use itertools::join;
+use glob::{glob, Pattern};
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderMap;
 use scraper::{Html, Selector};
+use sha2::{Digest, Sha256};
 use simple_error::SimpleError;
 
+use crate::dpkg::{DpkgStatus, DEFAULT_STATUS_PATH};
+use crate::error::{CommandError, Error, HtmlParseError};
+use crate::http::{HttpClient, NetworkConfig};
+use crate::metadata::MetadataCache;
+use crate::runner::{CommandOutput, CommandRunner};
+
 /// Package version with comparison traits
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct PackageVersion {
@@ -34,6 +41,24 @@ impl PartialOrd for PackageVersion {
     }
 }
 
+impl PackageVersion {
+    /// Get version of the source package, without the '+bN' suffix of binary only rebuilds (binNMU)
+    pub fn source_version(&self) -> PackageVersion {
+        let string = match self.string.rfind("+b") {
+            Some(i)
+                if (self.string.len() > i + 2)
+                    && self.string[i + 2..].chars().all(|c| c.is_ascii_digit()) =>
+            {
+                &self.string[..i]
+            }
+            _ => &self.string,
+        };
+        PackageVersion {
+            string: string.to_string(),
+        }
+    }
+}
+
 impl fmt::Display for PackageVersion {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.string)
@@ -55,25 +80,43 @@ pub struct Package {
 }
 
 /// Dependency version relation
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum PackageVersionRelation {
     Any,
     StrictlyInferior,
     InferiorOrEqual,
     Equal,
+    EqualWildcard,
     SuperiorOrEqual,
     StriclySuperior,
 }
 
 /// Package version constraint
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PackageVersionConstaint {
     pub version: PackageVersion,
     pub version_relation: PackageVersionRelation,
 }
 
+impl PackageVersionConstaint {
+    /// Check if a version satisfies the constraint
+    pub fn matches(&self, version: &PackageVersion) -> bool {
+        match self.version_relation {
+            PackageVersionRelation::Any => true,
+            PackageVersionRelation::StrictlyInferior => version < &self.version,
+            PackageVersionRelation::InferiorOrEqual => version <= &self.version,
+            PackageVersionRelation::Equal => version == &self.version,
+            PackageVersionRelation::EqualWildcard => Pattern::new(&self.version.string)
+                .map(|p| p.matches(&version.string))
+                .unwrap_or(false),
+            PackageVersionRelation::SuperiorOrEqual => version >= &self.version,
+            PackageVersionRelation::StriclySuperior => version > &self.version,
+        }
+    }
+}
+
 /// Package dependency
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PackageDependency {
     pub package_name: String,
 
@@ -82,7 +125,10 @@ pub struct PackageDependency {
 
 impl fmt::Display for PackageDependency {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for version_constraint in &self.version_constraints {
+        for (i, version_constraint) in self.version_constraints.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
             match version_constraint.version_relation {
                 PackageVersionRelation::Any => {
                     write!(f, "{}", self.package_name)?;
@@ -93,7 +139,7 @@ impl fmt::Display for PackageDependency {
                 PackageVersionRelation::InferiorOrEqual => {
                     write!(f, "{}<={}", self.package_name, version_constraint.version)?;
                 }
-                PackageVersionRelation::Equal => {
+                PackageVersionRelation::Equal | PackageVersionRelation::EqualWildcard => {
                     write!(f, "{}={}", self.package_name, version_constraint.version)?;
                 }
                 PackageVersionRelation::SuperiorOrEqual => {
@@ -109,108 +155,396 @@ impl fmt::Display for PackageDependency {
     }
 }
 
+impl PackageDependency {
+    /// Check if the dependency has no version constraint
+    pub fn is_unversioned(&self) -> bool {
+        self.version_constraints
+            .iter()
+            .all(|c| matches!(c.version_relation, PackageVersionRelation::Any))
+    }
+
+    /// Check if a package satisfies all version constraints of the dependency
+    pub fn is_satisfied_by(&self, package: &Package) -> bool {
+        package.name == self.package_name
+            && self
+                .version_constraints
+                .iter()
+                .all(|c| c.matches(&package.version))
+    }
+}
+
+impl FromStr for PackageDependency {
+    type Err = SimpleError;
+
+    /// Parse a package specification like 'name', 'name=version', 'name<<version' or 'name=version.*'
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(SimpleError::new("Empty package specification"));
+        }
+        let relation_start = match s.find(|c| "<=>".contains(c)) {
+            Some(i) => i,
+            None => {
+                return Ok(PackageDependency {
+                    package_name: s.to_string(),
+                    version_constraints: vec![PackageVersionConstaint {
+                        version: PackageVersion {
+                            string: "".to_string(),
+                        },
+                        version_relation: PackageVersionRelation::Any,
+                    }],
+                });
+            }
+        };
+        let (package_name, relation_and_version) = s.split_at(relation_start);
+        if package_name.is_empty() {
+            return Err(SimpleError::new(format!("Missing package name in {:?}", s)));
+        }
+        let relation_len = relation_and_version
+            .chars()
+            .take_while(|c| "<=>".contains(*c))
+            .count();
+        let (relation, version) = relation_and_version.split_at(relation_len);
+        let is_wildcard = version.contains('*');
+        let version_relation = match relation {
+            "<<" => PackageVersionRelation::StrictlyInferior,
+            "<=" => PackageVersionRelation::InferiorOrEqual,
+            "=" if is_wildcard => PackageVersionRelation::EqualWildcard,
+            "=" => PackageVersionRelation::Equal,
+            ">=" => PackageVersionRelation::SuperiorOrEqual,
+            ">>" => PackageVersionRelation::StriclySuperior,
+            r => {
+                return Err(SimpleError::new(format!(
+                    "Unexpected version relation {:?} in {:?}",
+                    r, s
+                )));
+            }
+        };
+        // Epochs are stripped everywhere else, so do the same here
+        let version = version.rsplit(':').next().unwrap_or(version);
+        if version.is_empty() {
+            return Err(SimpleError::new(format!("Missing version in {:?}", s)));
+        }
+        if is_wildcard {
+            match version_relation {
+                PackageVersionRelation::EqualWildcard => {
+                    Pattern::new(version).map_err(|e| {
+                        SimpleError::new(format!("Invalid version pattern in {:?}: {}", s, e))
+                    })?;
+                }
+                _ => {
+                    return Err(SimpleError::new(format!(
+                        "Version wildcards can only be used with '=' in {:?}",
+                        s
+                    )));
+                }
+            }
+        }
+
+        Ok(PackageDependency {
+            package_name: package_name.to_string(),
+            version_constraints: vec![PackageVersionConstaint {
+                version: PackageVersion {
+                    string: version.to_string(),
+                },
+                version_relation,
+            }],
+        })
+    }
+}
+
 /// APT environement configuration values
 pub struct AptEnv {
     arch: String,
-    cache_dir: String,
+    pub cache_dir: String,
+    pub network: NetworkConfig,
+    /// Base URLs of archive mirrors, with trailing '/'
+    pub mirrors: Vec<String>,
+    /// dpkg status database path
+    pub dpkg_status: PathBuf,
+    /// Alternate root directory to operate on instead of the live system
+    pub root: Option<PathBuf>,
+    /// Also follow Recommends of packages to install, like APT::Install-Recommends
+    pub install_recommends: bool,
+    /// Also follow Suggests of packages to install, like APT::Install-Suggests
+    pub install_suggests: bool,
     // TODO add distro & release
 }
 
-/// Read APT environment values
-pub fn read_apt_env() -> Result<AptEnv, Box<dyn error::Error>> {
-    let output = Command::new("apt-config")
-        .args(vec![
-            "shell",
-            "CACHE_ROOT_DIR",
-            "Dir::Cache",
-            "CACHE_ARCHIVE_SUBDIR",
-            "Dir::Cache::archives",
-            "ARCH",
-            "APT::Architecture",
-        ])
-        .env("LANG", "C")
-        .stderr(Stdio::null())
-        .output()?;
-    if !output.status.success() {
-        return Err(Box::new(SimpleError::new("apt-config failed")));
-    }
-    let lines: Vec<String> = output.stdout.lines().filter_map(Result::ok).collect();
-    let cache_root_dir = lines
-        .iter()
-        .find(|l| l.starts_with("CACHE_ROOT_DIR="))
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?
-        .split('\'')
-        .nth(1)
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?;
-    let archive_subdir = lines
-        .iter()
-        .find(|l| l.starts_with("CACHE_ARCHIVE_SUBDIR="))
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?
-        .split('\'')
-        .nth(1)
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?;
-    let arch = lines
-        .iter()
-        .find(|l| l.starts_with("ARCH="))
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?
-        .split('\'')
-        .nth(1)
-        .ok_or_else(|| SimpleError::new("Unexpected apt-config output"))?
-        .to_string();
+#[cfg(test)]
+impl AptEnv {
+    /// Build APT environment for tests, on amd64 with default paths
+    pub fn for_tests() -> AptEnv {
+        AptEnv {
+            arch: "amd64".to_string(),
+            cache_dir: "/var/cache/apt/archives/".to_string(),
+            network: NetworkConfig::default(),
+            mirrors: vec![DEFAULT_MIRROR.to_string()],
+            dpkg_status: PathBuf::from(DEFAULT_STATUS_PATH),
+            root: None,
+            install_recommends: false,
+            install_suggests: false,
+        }
+    }
+}
 
-    let cache_dir = format!("/{}/{}", cache_root_dir, archive_subdir);
+/// Run an APT command line tool, pointed at an alternate root directory if any
+fn run_apt(
+    runner: &dyn CommandRunner,
+    root: Option<&Path>,
+    program: &str,
+    args: &[&str],
+) -> io::Result<CommandOutput> {
+    let options = get_root_apt_options(root);
+    let mut apt_args: Vec<&str> = options.iter().map(String::as_str).collect();
+    apt_args.extend_from_slice(args);
+    runner.run(program, &apt_args)
+}
 
-    Ok(AptEnv { cache_dir, arch })
+/// Run an APT command line tool, pointed at an alternate root directory if any, and get its standard output
+fn run_apt_checked(
+    runner: &dyn CommandRunner,
+    root: Option<&Path>,
+    program: &str,
+    args: &[&str],
+) -> Result<String, Error> {
+    let options = get_root_apt_options(root);
+    let mut apt_args: Vec<&str> = options.iter().map(String::as_str).collect();
+    apt_args.extend_from_slice(args);
+    runner.run_checked(program, &apt_args)
 }
 
-/// Error generated when a command returns non zero code
-#[derive(Debug)]
-struct CommandError {
-    status: std::process::ExitStatus,
-    cmd: Vec<String>,
+/// Get APT options to operate on an alternate root directory
+fn get_root_apt_options(root: Option<&Path>) -> Vec<String> {
+    match root {
+        Some(root) => vec!["-o".to_string(), format!("Dir={}", root.to_string_lossy())],
+        None => Vec::new(),
+    }
 }
 
-impl fmt::Display for CommandError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Command {} ", join(&self.cmd, " "))?;
-        match self.status.code() {
-            Some(code) => write!(f, "returned {}", code),
-            None => write!(f, "killed by signal {}", self.status.signal().unwrap()),
+/// Mirror used if none is found in APT sources
+pub const DEFAULT_MIRROR: &str = "http://deb.debian.org/debian/";
+
+/// Add trailing '/' to a mirror base URL if missing
+pub fn normalize_mirror_url(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/", url)
+    }
+}
+
+/// Parse 'apt-get indextargets' repository URIs, and get the ones that look like a distribution archive mirror
+fn parse_mirrors(indextargets_output: &str) -> Vec<String> {
+    let mut mirrors: Vec<String> = Vec::new();
+    for uri in indextargets_output.lines().map(str::trim) {
+        let uri = normalize_mirror_url(uri);
+        if (uri.starts_with("http://") || uri.starts_with("https://"))
+            && (uri.ends_with("/debian/") || uri.ends_with("/ubuntu/"))
+            && !mirrors.contains(&uri)
+        {
+            mirrors.push(uri);
         }
     }
+    mirrors
 }
 
-impl error::Error for CommandError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
+/// Get archive mirrors from APT sources, or the default mirror if none is found
+fn read_apt_mirrors(runner: &dyn CommandRunner, root: Option<&Path>) -> Vec<String> {
+    let output = run_apt_checked(
+        runner,
+        root,
+        "apt-get",
+        &[
+            "indextargets",
+            "--format",
+            "$(REPO_URI)",
+            "Identifier: Packages",
+        ],
+    );
+    let mirrors = match output {
+        Ok(output) => parse_mirrors(&output),
+        Err(_) => Vec::new(),
+    };
+    if mirrors.is_empty() {
+        debug!("No mirror found in APT sources, using {}", DEFAULT_MIRROR);
+        vec![DEFAULT_MIRROR.to_string()]
+    } else {
+        mirrors
     }
 }
 
-fn download_package(package: &mut Package) -> Result<(), Box<dyn error::Error>> {
-    // Build target dir
+/// Get candidate URLs for a package URL, the same pool file on each mirror in order of preference, followed by the URL itself
+pub fn get_mirror_urls(url: &str, mirrors: &[String]) -> Vec<String> {
+    let mut urls = Vec::new();
+    if let Some(i) = url.find("/pool/") {
+        for mirror in mirrors {
+            let mirror_url = format!("{}{}", mirror, &url[i + 1..]);
+            if !urls.contains(&mirror_url) {
+                urls.push(mirror_url);
+            }
+        }
+    }
+    if !urls.iter().any(|u| u == url) {
+        urls.push(url.to_string());
+    }
+    urls
+}
+
+/// Parse 'apt-config shell' output to a map of variable name to value
+fn parse_apt_config_shell(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| {
+            let mut tokens = l.splitn(2, '=');
+            let name = tokens.next()?;
+            let value = tokens.next()?.trim_matches('\'');
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Get proxy URL from APT proxy configuration value, 'DIRECT' meaning no proxy
+fn parse_apt_proxy(value: Option<&String>) -> Option<String> {
+    match value {
+        Some(v) if !v.is_empty() && (v != "DIRECT") => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Build APT environment from 'apt-config shell' variables
+fn parse_apt_env(values: &HashMap<String, String>) -> Result<AptEnv, Error> {
+    let get_value = |name| {
+        values
+            .get(name)
+            .ok_or_else(|| Error::parse("apt-config output", &format!("missing {}", name)))
+    };
+    let cache_dir = get_value("CACHE_DIR")?.to_string();
+    let arch = get_value("ARCH")?.to_string();
+    let dpkg_status = PathBuf::from(
+        values
+            .get("DPKG_STATUS")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_STATUS_PATH),
+    );
+
+    let mut network = NetworkConfig {
+        http_proxy: parse_apt_proxy(values.get("HTTP_PROXY")),
+        https_proxy: parse_apt_proxy(values.get("HTTPS_PROXY")),
+        ..Default::default()
+    };
+    if let Some(retries) = values.get("RETRIES") {
+        network.retries = retries.parse().map_err(|_| {
+            Error::parse(
+                "apt-config output",
+                &format!("invalid RETRIES {:?}", retries),
+            )
+        })?;
+    }
+    if let Some(timeout) = values.get("TIMEOUT") {
+        network.timeout = Duration::from_secs(timeout.parse().map_err(|_| {
+            Error::parse(
+                "apt-config output",
+                &format!("invalid TIMEOUT {:?}", timeout),
+            )
+        })?);
+    }
+
+    Ok(AptEnv {
+        cache_dir,
+        arch,
+        network,
+        mirrors: Vec::new(),
+        dpkg_status,
+        root: None,
+        install_recommends: false,
+        install_suggests: false,
+    })
+}
+
+/// Read APT environment values, for an alternate root directory if any
+pub fn read_apt_env(runner: &dyn CommandRunner, root: Option<&Path>) -> Result<AptEnv, Error> {
+    let apt_args = [
+        "shell",
+        "CACHE_DIR",
+        "Dir::Cache::archives/d",
+        "ARCH",
+        "APT::Architecture",
+        "HTTP_PROXY",
+        "Acquire::http::Proxy",
+        "HTTPS_PROXY",
+        "Acquire::https::Proxy",
+        "RETRIES",
+        "Acquire::Retries",
+        "TIMEOUT",
+        "Acquire::http::Timeout",
+        "DPKG_STATUS",
+        "Dir::State::status/f",
+    ];
+    let output = run_apt_checked(runner, root, "apt-config", &apt_args)?;
+    let values = parse_apt_config_shell(&output);
+
+    let mut apt_env = parse_apt_env(&values)?;
+    apt_env.mirrors = read_apt_mirrors(runner, root);
+    apt_env.root = root.map(Path::to_path_buf);
+    Ok(apt_env)
+}
+
+/// Get directory where downloaded packages are stored
+pub fn get_download_cache_dir() -> Result<PathBuf, Error> {
     let dirs = ProjectDirs::from("", "Desbma", "APT Downgrade")
-        .ok_or_else(|| SimpleError::new("Unable to compute cache dir"))?;
-    let cache_dir = dirs.cache_dir();
-    fs::create_dir_all(cache_dir)?;
+        .ok_or_else(|| Error::Other("Unable to compute cache dir".to_string()))?;
+    Ok(dirs.cache_dir().to_path_buf())
+}
 
-    // Build target filepath
-    let url = package.url.as_ref().unwrap();
+/// Get filepath a package URL is downloaded to
+pub fn get_download_filepath(url: &str) -> Result<PathBuf, Error> {
     let filename = url
         .rsplit('/')
         .next()
-        .ok_or_else(|| SimpleError::new("Unable to extract filename from URL"))?;
-    let filepath_final = cache_dir.join(&filename);
+        .ok_or_else(|| Error::parse(url, "unable to extract filename from URL"))?;
+    Ok(get_download_cache_dir()?.join(filename))
+}
+
+/// Compute SHA256 hash of a file, as an hex string
+pub fn get_file_sha256(filepath: &Path) -> Result<String, Error> {
+    let mut file = File::open(filepath)?;
+    let mut hasher = Sha256::new();
+    copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Download a package file to the download cache if it is not already there, and set its filepath
+pub fn download_package(
+    package: &mut Package,
+    http_client: &HttpClient,
+    metadata_cache: &mut MetadataCache,
+    apt_env: &AptEnv,
+) -> Result<(), Error> {
+    // Build target dir
+    let cache_dir = get_download_cache_dir()?;
+    fs::create_dir_all(&cache_dir)?;
+
+    // Build target filepath
+    let url = package.url.as_ref().unwrap();
+    let filepath_final = get_download_filepath(url)?;
+    let filename = filepath_final
+        .file_name()
+        .ok_or_else(|| Error::parse(url, "unable to extract filename from URL"))?
+        .to_string_lossy()
+        .to_string();
 
     if filepath_final.exists() {
         info!("Got {:?} from cache in {:?}", url, filepath_final);
     } else {
         // Download
         info!("Downloading {:?} to {:?}", url, filepath_final);
-        let mut response = reqwest::blocking::get(url)?.error_for_status()?;
+        let (_, mut response) =
+            http_client.get_first(&get_mirror_urls(url, &apt_env.mirrors), HeaderMap::new())?;
         let filepath_tmp = cache_dir.join(format!("{}.tmp", filename));
         let mut target_file = File::create(&filepath_tmp)?;
         copy(&mut response, &mut target_file)?;
         drop(target_file);
+        metadata_cache.set_download_sha256(&filename, &get_file_sha256(&filepath_tmp)?);
         fs::rename(&filepath_tmp, &filepath_final)?;
     }
 
@@ -219,145 +553,169 @@ fn download_package(package: &mut Package) -> Result<(), Box<dyn error::Error>>
         filepath_final
             .into_os_string()
             .into_string()
-            .or_else(|_| Err(SimpleError::new("Unexpected filename")))?,
+            .or_else(|_| Err(Error::Other("Unexpected filename".to_string())))?,
     );
 
     // All good
     Ok(())
 }
 
+/// Dependencies of a package, by relationship field
+#[derive(Debug, Default)]
+pub struct PackageDependencies {
+    pub depends: Vec<PackageDependency>,
+
+    /// Groups of alternatives
+    pub recommends: Vec<Vec<PackageDependency>>,
+
+    /// Groups of alternatives
+    pub suggests: Vec<Vec<PackageDependency>>,
+}
+
 /// Get dependencies for a package
 pub fn get_dependencies(
     mut package: &mut Package,
-) -> Result<Vec<PackageDependency>, Box<dyn error::Error>> {
-    let mut deps = Vec::new();
-
+    runner: &dyn CommandRunner,
+    http_client: &HttpClient,
+    metadata_cache: &mut MetadataCache,
+    apt_env: &AptEnv,
+) -> Result<PackageDependencies, Error> {
     if package.filepath.is_none() {
-        download_package(&mut package)?;
+        download_package(&mut package, http_client, metadata_cache, apt_env)?;
     }
 
     let deb_filepath = package.filepath.as_ref().unwrap();
     let spec = format!("{}={}", package.name, package.version);
     let apt_args = if Path::new(&deb_filepath).is_file() {
-        vec!["show", &deb_filepath]
+        ["show", deb_filepath]
     } else {
-        vec!["show", &spec]
+        ["show", &spec]
     };
 
-    let output = Command::new("apt-cache")
-        .args(&apt_args)
-        .env("LANG", "C")
-        .stderr(Stdio::null())
-        .output()?;
-    if !output.status.success() {
-        let mut cmd: Vec<String> = vec!["apt-cache".to_string()];
-        cmd.extend(apt_args.iter().map(|s| (*s).to_string()));
-        return Err(Box::new(CommandError {
-            status: output.status,
-            cmd,
-        }));
-    }
-    let line_prefix = "Depends: ";
-    let package_desc_line = output
-        .stdout
-        .lines()
-        .filter_map(Result::ok)
-        .find(|l| l.starts_with(line_prefix))
-        .ok_or_else(|| SimpleError::new("Unexpected apt-cache output"))?;
+    let output = run_apt_checked(runner, apt_env.root.as_deref(), "apt-cache", &apt_args)?;
+    parse_dependencies(&output)
+}
 
-    // TODO parse multiple version constraints for a single package
+/// Parse dependencies from the Depends, Recommends & Suggests fields of 'apt-cache show' output
+fn parse_dependencies(show_output: &str) -> Result<PackageDependencies, Error> {
+    // Fields of the first record only, if several versions are shown, all of them are optional
+    let record = show_output.split("\n\n").next().unwrap();
+    let parse_field = |name| match get_control_field(record, name) {
+        Some(value) => parse_dependency_field(&value, "apt-cache show output"),
+        None => Ok(Vec::new()),
+    };
 
-    for package_desc in package_desc_line
-        .split_at(line_prefix.len())
-        .1
-        .split(',')
-        .map(|l| l.trim_start())
-    {
-        let mut package_desc_tokens = package_desc
-            .split('|') // TODO handle 'or' constraints
-            .next()
-            .ok_or_else(|| SimpleError::new("Unexpected apt-cache output"))?
-            .trim_end()
-            .split(' ');
-        let package_name = package_desc_tokens
-            .next()
-            .ok_or_else(|| SimpleError::new("Unexpected apt-cache output"))?
-            .to_string();
-        let package_version_relation_raw = &package_desc_tokens.next();
-        let package_version_relation = match package_version_relation_raw {
-            Some(r) => match &r[1..] {
-                "<<" => PackageVersionRelation::StrictlyInferior,
-                "<=" => PackageVersionRelation::InferiorOrEqual,
-                "=" => PackageVersionRelation::Equal,
-                ">=" => PackageVersionRelation::SuperiorOrEqual,
-                ">>" => PackageVersionRelation::StriclySuperior,
-                r => {
-                    panic!("Unexpected version relation: {}", r);
-                }
-            },
-            None => PackageVersionRelation::Any,
-        };
-        let package_version = match package_version_relation {
-            PackageVersionRelation::Any => "",
-            _ => {
-                let package_version_raw = &package_desc_tokens
-                    .next()
-                    .ok_or_else(|| SimpleError::new("Unexpected apt-cache output"))?;
-                &package_version_raw[0..&package_version_raw.len() - 1]
-                    .rsplit(':')
-                    .next()
-                    .ok_or_else(|| SimpleError::new("Unexpected apt-cache output"))?
-            }
-        };
+    // TODO handle 'or' constraints
+    let depends = parse_field("Depends")?
+        .into_iter()
+        .map(|alternatives| {
+            alternatives
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::parse("apt-cache show output", "unexpected Depends field"))
+        })
+        .collect::<Result<Vec<PackageDependency>, Error>>()?;
+
+    Ok(PackageDependencies {
+        depends,
+        recommends: parse_field("Recommends")?,
+        suggests: parse_field("Suggests")?,
+    })
+}
 
-        deps.push(PackageDependency {
-            package_name,
-            version_constraints: vec![PackageVersionConstaint {
-                version: PackageVersion {
-                    string: package_version.to_string(),
-                },
-                version_relation: package_version_relation,
-            }],
-        });
+/// Parse a dependency field value like 'a (>= 1.0), b | c', into groups of alternatives
+pub fn parse_dependency_field(
+    value: &str,
+    context: &str,
+) -> Result<Vec<Vec<PackageDependency>>, Error> {
+    let mut deps = Vec::new();
+    for group_desc in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let alternatives = group_desc
+            .split('|')
+            .map(|d| parse_dependency_desc(d.trim(), context))
+            .collect::<Result<Vec<PackageDependency>, Error>>()?;
+        deps.push(alternatives);
     }
-
     Ok(deps)
 }
 
+/// Parse a single dependency like 'name (>= version)'
+fn parse_dependency_desc(package_desc: &str, context: &str) -> Result<PackageDependency, Error> {
+    // TODO parse multiple version constraints for a single package
+    let mut package_desc_tokens = package_desc.split(' ');
+    let package_name = package_desc_tokens
+        .next()
+        .ok_or_else(|| Error::parse(context, "unexpected dependency field"))?
+        // Architecture qualifier, ie. 'python3:any'
+        .split(':')
+        .next()
+        .unwrap()
+        .to_string();
+    let package_version_relation_raw = &package_desc_tokens.next();
+    let package_version_relation = match package_version_relation_raw {
+        Some(r) => match &r[1..] {
+            "<<" => PackageVersionRelation::StrictlyInferior,
+            "<=" => PackageVersionRelation::InferiorOrEqual,
+            "=" => PackageVersionRelation::Equal,
+            ">=" => PackageVersionRelation::SuperiorOrEqual,
+            ">>" => PackageVersionRelation::StriclySuperior,
+            r => {
+                return Err(Error::parse(
+                    context,
+                    &format!("unexpected version relation {:?}", r),
+                ));
+            }
+        },
+        None => PackageVersionRelation::Any,
+    };
+    let package_version = match package_version_relation {
+        PackageVersionRelation::Any => "",
+        _ => {
+            let package_version_raw = &package_desc_tokens
+                .next()
+                .ok_or_else(|| Error::parse(context, "unexpected dependency field"))?;
+            &package_version_raw[0..&package_version_raw.len() - 1]
+                .rsplit(':')
+                .next()
+                .ok_or_else(|| Error::parse(context, "unexpected dependency field"))?
+        }
+    };
+
+    Ok(PackageDependency {
+        package_name,
+        version_constraints: vec![PackageVersionConstaint {
+            version: PackageVersion {
+                string: package_version.to_string(),
+            },
+            version_relation: package_version_relation,
+        }],
+    })
+}
+
 /// Find the best package version that satisfies a dependency constraint
 pub fn resolve_dependency(
     dependency: &PackageDependency,
     candidates: Vec<Package>,
     installed_package: &Option<Package>,
 ) -> Option<Package> {
-    let mut matching_candidates: Box<dyn std::iter::Iterator<Item = &Package>> =
-        Box::new(candidates.iter());
-    for constraint in &dependency.version_constraints {
-        let filter_predicate: Box<dyn Fn(&&Package) -> bool> = match constraint.version_relation {
-            PackageVersionRelation::Any => Box::new(|_p| true),
-            PackageVersionRelation::StrictlyInferior => {
-                Box::new(move |p| p.version < constraint.version)
-            }
-            PackageVersionRelation::InferiorOrEqual => {
-                Box::new(move |p| p.version <= constraint.version)
-            }
-            PackageVersionRelation::Equal => Box::new(move |p| p.version == constraint.version),
-            PackageVersionRelation::SuperiorOrEqual => {
-                Box::new(move |p| p.version >= constraint.version)
-            }
-            PackageVersionRelation::StriclySuperior => {
-                Box::new(move |p| p.version > constraint.version)
-            }
-        };
-
-        matching_candidates = Box::new(matching_candidates.filter(filter_predicate));
-    }
+    let matching_candidates = candidates.iter().filter(|p| {
+        dependency
+            .version_constraints
+            .iter()
+            .all(|c| c.matches(&p.version))
+    });
 
-    // If installed package matches, return it
+    // If installed package matches, return it, with the location of the same version and architecture if known,
+    // even if its package file is not available anymore
     let matching_candidates: Vec<&Package> = matching_candidates.collect();
     if let Some(installed_package) = installed_package {
-        if matching_candidates.contains(&installed_package) {
-            return Some(installed_package.clone());
+        if dependency.is_satisfied_by(installed_package) {
+            let same_candidate = matching_candidates.iter().find(|p| {
+                (p.version == installed_package.version) && (p.arch == installed_package.arch)
+            });
+            return Some(
+                same_candidate.map_or_else(|| installed_package.clone(), |p| (*p).clone()),
+            );
         }
     }
 
@@ -366,114 +724,78 @@ pub fn resolve_dependency(
 }
 
 /// Get the package version currently installed if any
-pub fn get_installed_version(package_name: &str, apt_env: &AptEnv) -> Option<Package> {
-    // Get version
-    let output = Command::new("apt-cache")
-        .args(vec!["policy", package_name])
-        .env("LANG", "C")
-        .stderr(Stdio::null())
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        return None;
-    }
-    let line_prefix = "  Installed: ";
-    let package_version_line = output
-        .stdout
-        .lines()
-        .filter_map(Result::ok)
-        .find(|l| l.starts_with(line_prefix))?;
-    let package_version = package_version_line
-        .split_at(line_prefix.len())
-        .1
-        .rsplit(':')
-        .next()?;
-    if package_version == "(none)" {
-        return None;
-    }
-
-    // Get filename
-    let output = Command::new("apt-cache")
-        .args(vec!["show", package_name])
-        .env("LANG", "C")
-        .stderr(Stdio::null())
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        return None;
-    }
-    let lines: Vec<String> = output.stdout.lines().filter_map(Result::ok).collect();
-    let line_prefix = "Filename: ";
-    let package_filename_line = lines.iter().find(|l| l.starts_with(line_prefix))?;
-    let package_filename = Path::new(package_filename_line.split_at(line_prefix.len()).1)
-        .file_name()?
-        .to_str()?
-        .to_string();
-
-    // Get architecture
-    let line_prefix = "Architecture: ";
-    let package_arch_line = lines.iter().find(|l| l.starts_with(line_prefix))?;
-    let package_arch = package_arch_line.split_at(line_prefix.len()).1;
+pub fn get_installed_version(
+    package_name: &str,
+    dpkg_status: &DpkgStatus,
+    apt_env: &AptEnv,
+) -> Option<Package> {
+    let installed_package = dpkg_status.get_installed(package_name, &apt_env.arch)?;
 
     debug!(
         "Installed version for {}: {} ({})",
-        package_name, package_version, package_arch
+        package_name, installed_package.version, installed_package.arch
+    );
+
+    // Path the package file has in APT archive cache if it was installed with APT
+    let package_filename = format!(
+        "{}_{}_{}.deb",
+        package_name,
+        installed_package.version.replace(':', "%3a"),
+        installed_package.arch
     );
 
     Some(Package {
         name: package_name.to_string(),
         version: PackageVersion {
-            string: package_version.to_string(),
+            string: installed_package
+                .version
+                .rsplit(':')
+                .next()
+                .unwrap()
+                .to_string(),
         },
-        arch: Some(package_arch.to_string()),
+        arch: Some(installed_package.arch.clone()),
         filepath: Some(format!("{}{}", apt_env.cache_dir, package_filename)),
         url: None,
     })
 }
 
-/// Get all versions of a package currently in local cache
+/// Get all versions of a package currently in a local cache directory
 pub fn get_cache_package_versions(
     package_name: &str,
+    cache_dir: &str,
     apt_env: &AptEnv,
-) -> Result<Vec<Package>, Box<dyn error::Error>> {
+) -> Result<Vec<Package>, Error> {
     let mut versions = Vec::new();
 
     for arch in &[apt_env.arch.clone(), "all".to_string(), "any".to_string()] {
-        for path_entry in glob(&format!(
-            "{}{}_*_{}.deb",
-            apt_env.cache_dir, package_name, arch
-        ))?
-        .filter_map(Result::ok)
+        let pattern = Path::new(cache_dir).join(format!("{}_*_{}.deb", package_name, arch));
+        for path_entry in glob(&pattern.to_string_lossy())
+            .map_err(|e| Error::parse(cache_dir, &e.to_string()))?
+            .filter_map(Result::ok)
         {
             let path = path_entry
                 .file_name()
-                .ok_or_else(|| {
-                    SimpleError::new(format!("Unexpected entry in {}", apt_env.cache_dir))
-                })?
+                .ok_or_else(|| Error::parse(cache_dir, "unexpected entry"))?
                 .to_os_string()
                 .into_string()
-                .or_else(|_| {
-                    Err(SimpleError::new(format!(
-                        "Unexpected entry in {}",
-                        apt_env.cache_dir
-                    )))
-                })?;
+                .map_err(|_| Error::parse(cache_dir, "unexpected entry"))?;
             let mut tokens = path.split('_').rev();
             let arch = tokens
                 .next()
-                .ok_or_else(|| SimpleError::new(format!("Unexpected package filename: {}", path)))?
+                .ok_or_else(|| Error::parse(&path, "unexpected package filename"))?
                 .split('.')
                 .next()
-                .ok_or_else(|| SimpleError::new(format!("Unexpected package filename: {}", path)))?
+                .ok_or_else(|| Error::parse(&path, "unexpected package filename"))?
                 .to_string();
             let mut version = tokens
                 .next()
-                .ok_or_else(|| SimpleError::new(format!("Unexpected package filename: {}", path)))?
+                .ok_or_else(|| Error::parse(&path, "unexpected package filename"))?
                 .replace("%3a", ":"); // TODO better urlescape
             version = version
                 .rsplit(':')
                 .next()
-                .ok_or_else(|| SimpleError::new(format!("Unexpected package filename: {}", path)))?
+                .ok_or_else(|| Error::parse(&path, "unexpected package filename"))?
                 .to_string();
             debug!("Local version for {}: {} ({})", package_name, version, arch);
             versions.push(Package {
@@ -486,7 +808,7 @@ pub fn get_cache_package_versions(
                     path_entry
                         .into_os_string()
                         .into_string()
-                        .or_else(|_| Err(SimpleError::new("Unable to convert OS string")))?,
+                        .or_else(|_| Err(Error::parse(cache_dir, "unexpected entry")))?,
                 ),
                 url: None,
             });
@@ -496,93 +818,327 @@ pub fn get_cache_package_versions(
     Ok(versions)
 }
 
-pub fn get_package_index_url(
+/// Get a single line field value from a control record
+pub fn get_control_field(record: &str, name: &str) -> Option<String> {
+    let prefix = format!("{}: ", name);
+    record
+        .lines()
+        .find(|l| l.starts_with(&prefix))
+        .map(|l| l.split_at(prefix.len()).1.to_string())
+}
+
+/// Read package name, version and architecture from a .deb file control data
+pub fn read_deb_control(filepath: &Path, runner: &dyn CommandRunner) -> Result<Package, Error> {
+    let filepath_str = filepath
+        .to_str()
+        .ok_or_else(|| Error::Other(format!("Unexpected filename: {:?}", filepath)))?;
+    let control = runner.run_checked(
+        "dpkg-deb",
+        &[
+            "--field",
+            filepath_str,
+            "Package",
+            "Version",
+            "Architecture",
+        ],
+    )?;
+    let get_field = |name| {
+        get_control_field(&control, name).ok_or_else(|| {
+            Error::parse(
+                &format!("dpkg-deb output for {:?}", filepath),
+                &format!("missing {} field", name),
+            )
+        })
+    };
+    let name = get_field("Package")?;
+    let version = get_field("Version")?;
+    let arch = get_field("Architecture")?;
+
+    Ok(Package {
+        name,
+        version: PackageVersion {
+            string: version.rsplit(':').next().unwrap().to_string(),
+        },
+        arch: Some(arch),
+        filepath: Some(filepath_str.to_string()),
+        url: None,
+    })
+}
+
+/// Get SHA256 of a package file from APT indexes, None if the package is not in any index
+pub fn get_repo_package_sha256(
     package_name: &str,
+    version: &str,
+    arch: &str,
+    runner: &dyn CommandRunner,
     apt_env: &AptEnv,
-) -> Result<String, Box<dyn error::Error>> {
-    // TODO choose URL from distro
-    let mirrors_url = format!(
-        "https://packages.debian.org/sid/{}/{}/download",
-        apt_env.arch, package_name
-    );
-
-    // Download
-    debug!("GET {}", mirrors_url);
-    let html = reqwest::blocking::get(&mirrors_url)?
-        .error_for_status()?
-        .text()?;
+) -> Result<Option<String>, Error> {
+    let spec = format!("{}={}", package_name, version);
+    let output = run_apt(
+        runner,
+        apt_env.root.as_deref(),
+        "apt-cache",
+        &["show", &spec],
+    )?;
+    if !output.status.success() {
+        // apt-cache fails if the version is unknown
+        return Ok(None);
+    }
+    let show_output = output.stdout;
+    Ok(show_output
+        .split("\n\n")
+        .filter(|r| get_control_field(r, "Architecture").as_deref() == Some(arch))
+        .find_map(|r| get_control_field(r, "SHA256")))
+}
 
-    // Parse
-    let document = Html::parse_document(&html);
-    let selector = Selector::parse("a").unwrap();
-    let mut url = document
-        .select(&selector)
-        .map(|e| e.value().attr("href").unwrap())
-        .find(|u| u.starts_with("http://ftp.debian.org/debian/pool/"))
-        .ok_or_else(|| SimpleError::new("Unexpected HTML"))?
-        .rsplitn(2, '/')
-        .nth(1)
-        .ok_or_else(|| SimpleError::new("Unexpected HTML"))?
-        .to_string();
-    url.push('/');
+/// Find .deb files recursively in directories, and index the ones compatible with the system by package name
+pub fn scan_deb_dirs(
+    dirs: &[PathBuf],
+    runner: &dyn CommandRunner,
+    metadata_cache: &mut MetadataCache,
+    apt_env: &AptEnv,
+) -> HashMap<String, Vec<Package>> {
+    let mut packages: HashMap<String, Vec<Package>> = HashMap::new();
+    let arch_whitelist = [apt_env.arch.as_str(), "all", "any"];
+
+    for dir in dirs {
+        let pattern = dir.join("**").join("*.deb");
+        let paths = match glob(&pattern.to_string_lossy()) {
+            Ok(paths) => paths,
+            Err(e) => {
+                warn!("Unable to scan {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        for path in paths.filter_map(Result::ok) {
+            let package = match metadata_cache.get_deb(&path) {
+                Some(entry) => Package {
+                    name: entry.name,
+                    version: PackageVersion {
+                        string: entry.version,
+                    },
+                    arch: Some(entry.arch),
+                    filepath: Some(path.to_string_lossy().to_string()),
+                    url: None,
+                },
+                None => match read_deb_control(&path, runner) {
+                    Ok(package) => {
+                        metadata_cache.set_deb(
+                            &path,
+                            &package.name,
+                            &package.version.string,
+                            package.arch.as_ref().unwrap(),
+                        );
+                        package
+                    }
+                    Err(e) => {
+                        warn!("Unable to read {:?}: {}", path, e);
+                        continue;
+                    }
+                },
+            };
+            if !arch_whitelist.contains(&package.arch.as_ref().unwrap().as_str()) {
+                continue;
+            }
+            debug!(
+                "Directory version for {}: {} ({}) in {:?}",
+                package.name,
+                package.version,
+                package.arch.as_ref().unwrap(),
+                path
+            );
+            packages
+                .entry(package.name.clone())
+                .or_default()
+                .push(package);
+        }
+    }
 
-    Ok(url)
+    packages
 }
 
-/// Get all versions of a package from remote API
-pub fn get_remote_package_versions(
+/// Parse 'apt-cache policy' and 'apt-cache show' output to get package versions available from APT repositories
+fn parse_repo_package_versions(
     package_name: &str,
-    html_cache: &mut HashMap<String, String>,
+    policy_output: &str,
+    show_output: &str,
     apt_env: &AptEnv,
-) -> Result<Vec<Package>, Box<dyn error::Error>> {
+) -> Vec<Package> {
     let mut packages = Vec::new();
 
-    // Notes:
-    // * using directly index like http://ftp.debian.org/debian/pool/main/libr/libreoffice/
-    // is not reliable because directory is sometimes hard to deduce from package (ie. libasound2 is in alsa-lib dir)
-    // * the API at https://sources.debian.org/doc/api/ is incomplete so useless for our needs
-
-    // Get index URL
-    let index_url = get_package_index_url(package_name, apt_env)?;
-
-    // Download
-    let html = match html_cache.entry(index_url.clone()) {
-        Entry::Occupied(h) => {
-            trace!("Got {} from HTML cache", index_url);
-            h.get().clone()
+    // Get repository URIs for each version from policy version table
+    let mut version_uris: HashMap<String, Vec<String>> = HashMap::new();
+    let mut current_version: Option<String> = None;
+    for line in policy_output
+        .lines()
+        .skip_while(|l| !l.starts_with("  Version table:"))
+        .skip(1)
+    {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let is_priority = |s: &str| s.parse::<i32>().is_ok();
+        match tokens.as_slice() {
+            // Version line, not to be confused with a priority & source line like '100 /var/lib/dpkg/status'
+            ["***", version, priority] | [version, priority]
+                if is_priority(priority) && !is_priority(version) =>
+            {
+                current_version = Some((*version).to_string());
+            }
+            [_, uri, _, _, "Packages"] | [_, uri, _, "Packages"] => {
+                if let Some(current_version) = &current_version {
+                    version_uris
+                        .entry(current_version.clone())
+                        .or_default()
+                        .push((*uri).to_string());
+                }
+            }
+            _ => {}
         }
-        Entry::Vacant(e) => {
-            debug!("GET {}", index_url);
-            let html = reqwest::blocking::get(&index_url)?
-                .error_for_status()?
-                .text()?;
-            e.insert(html.clone());
-            html
+    }
+
+    // Get filename and architecture for each version
+    let arch_whitelist = [apt_env.arch.as_str(), "all", "any"];
+    for record in show_output.split("\n\n") {
+        let (version, arch, filename) = match (
+            get_control_field(record, "Version"),
+            get_control_field(record, "Architecture"),
+            get_control_field(record, "Filename"),
+        ) {
+            (Some(version), Some(arch), Some(filename)) => (version, arch, filename),
+            _ => continue,
+        };
+        if !arch_whitelist.contains(&arch.as_str()) {
+            continue;
         }
-    };
+        let uri = match version_uris.get(&version).and_then(|u| u.first()) {
+            Some(uri) => uri,
+            None => continue,
+        };
+        let file_url = format!(
+            "{}/{}",
+            uri.trim_end_matches('/'),
+            filename.trim_start_matches("./")
+        );
+        let (filepath, url) = if file_url.starts_with("file:") {
+            (
+                Some(format!(
+                    "/{}",
+                    file_url.trim_start_matches("file:").trim_start_matches('/')
+                )),
+                None,
+            )
+        } else {
+            (None, Some(file_url))
+        };
+        let version = version.rsplit(':').next().unwrap().to_string();
+        debug!(
+            "Repository version for {}: {} ({})",
+            package_name, version, arch
+        );
+        packages.push(Package {
+            name: package_name.to_string(),
+            version: PackageVersion { string: version },
+            arch: Some(arch),
+            filepath,
+            url,
+        });
+    }
 
-    // Parse
-    let document = Html::parse_document(&html);
-    let selector = Selector::parse("a").unwrap();
-    let filename_prefix = format!("{}_", package_name);
-    let arch_whitelist = [&apt_env.arch, "all", "any"];
-    for filename in document
+    packages
+}
+
+/// Get all versions of a package available from APT repositories
+pub fn get_repo_package_versions(
+    package_name: &str,
+    runner: &dyn CommandRunner,
+    apt_env: &AptEnv,
+) -> Result<Vec<Package>, Error> {
+    let root = apt_env.root.as_deref();
+    let policy_output = run_apt_checked(runner, root, "apt-cache", &["policy", package_name])?;
+    let show_output = run_apt_checked(runner, root, "apt-cache", &["show", package_name])?;
+
+    Ok(parse_repo_package_versions(
+        package_name,
+        &policy_output,
+        &show_output,
+        apt_env,
+    ))
+}
+
+/// Check if a package file is available without network access
+pub fn is_available_offline(package: &Package) -> bool {
+    match (&package.filepath, &package.url) {
+        (Some(filepath), _) => Path::new(filepath).is_file(),
+        (None, Some(url)) => get_download_filepath(url)
+            .map(|f| f.is_file())
+            .unwrap_or(false),
+        (None, None) => false,
+    }
+}
+
+/// Get the href attribute of all links of a HTML page, ignoring links without one
+fn get_html_links(html: &str) -> Vec<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("a[href]").unwrap();
+    document
         .select(&selector)
-        .map(|e| e.value().attr("href").unwrap())
-        .filter(|u| u.starts_with(&filename_prefix) && u.ends_with(".deb"))
-    {
-        let filename_noext = Path::new(filename)
-            .file_stem()
-            .unwrap()
-            .to_os_string()
-            .into_string()
-            .unwrap();
-        let mut tokens = filename_noext.rsplit('_');
-        let arch = tokens.next().unwrap();
-        if !arch_whitelist.contains(&arch) {
+        .filter_map(|e| e.value().attr("href"))
+        .map(|h| h.to_string())
+        .collect()
+}
+
+/// Parse packages.debian.org download page to get the package pool directory, relative to mirror base URL
+fn parse_pool_dir(url: &str, html: &str) -> Result<String, HtmlParseError> {
+    let link = get_html_links(html)
+        .into_iter()
+        .find(|h| h.contains("/pool/") && h.ends_with(".deb"))
+        .ok_or_else(|| HtmlParseError::new(url, "a[href*=\"/pool/\"]", "no download link"))?;
+    let element = format!("a[href=\"{}\"]", link);
+    let pool_path = &link[link.find("/pool/").unwrap() + 1..];
+    let (dir, _filename) = pool_path
+        .rsplit_once('/')
+        .ok_or_else(|| HtmlParseError::new(url, &element, "unexpected download link"))?;
+    Ok(format!("{}/", dir))
+}
+
+/// Parse pool directory index page to get all .deb filenames
+fn parse_index_filenames(html: &str) -> Vec<String> {
+    get_html_links(html)
+        .into_iter()
+        .filter(|h| !h.contains('/') && h.ends_with(".deb"))
+        .map(|h| percent_decode_str(&h).decode_utf8_lossy().to_string())
+        .collect()
+}
+
+/// Build packages from .deb filenames of a pool directory index, ignoring other packages and foreign architectures
+fn parse_remote_package_versions(
+    package_name: &str,
+    index_url: &str,
+    filenames: &[String],
+    apt_env: &AptEnv,
+) -> Result<Vec<Package>, HtmlParseError> {
+    let mut packages = Vec::new();
+    let filename_prefix = format!("{}_", package_name);
+    let arch_whitelist = [apt_env.arch.as_str(), "all", "any"];
+    for filename in filenames.iter().filter(|u| u.starts_with(&filename_prefix)) {
+        let element = format!("a[href=\"{}\"]", filename);
+        let tokens: Vec<&str> = filename
+            .strip_suffix(".deb")
+            .ok_or_else(|| HtmlParseError::new(index_url, &element, "not a .deb file"))?
+            .split('_')
+            .collect();
+        let (version, arch) = match tokens.as_slice() {
+            [_, version, arch] if !version.is_empty() && !arch.is_empty() => (version, arch),
+            _ => {
+                return Err(HtmlParseError::new(
+                    index_url,
+                    &element,
+                    "filename is not in 'name_version_arch.deb' format",
+                ))
+            }
+        };
+        if !arch_whitelist.contains(arch) {
             continue;
         }
-        let version = tokens.next().unwrap();
         debug!(
             "Remote version for {}: {} ({})",
             package_name, version, arch
@@ -590,36 +1146,136 @@ pub fn get_remote_package_versions(
         packages.push(Package {
             name: package_name.to_string(),
             version: PackageVersion {
-                string: version.to_string(),
+                string: version.rsplit(':').next().unwrap().to_string(),
             },
             arch: Some(arch.to_string()),
             filepath: None,
             url: Some(format!("{}{}", index_url, filename)),
         });
     }
-
     Ok(packages)
 }
 
-/// Build apt install command line for a list of packages
-pub fn build_install_cmdline(packages: Vec<Package>) -> Vec<String> {
-    let mut cmd = vec![
-        "apt-get".to_string(),
-        "install".to_string(),
-        "-V".to_string(),
-        "--no-install-recommends".to_string(),
-    ];
+/// Get directory of a package in the archive pool, relative to mirror base URL, ie. 'pool/main/a/alsa-lib/'
+pub fn get_package_pool_dir(
+    package_name: &str,
+    http_client: &HttpClient,
+    metadata_cache: &mut MetadataCache,
+    apt_env: &AptEnv,
+) -> Result<String, Error> {
+    // TODO choose URL from distro
+    let mirrors_url = format!(
+        "https://packages.debian.org/sid/{}/{}/download",
+        apt_env.arch, package_name
+    );
+
+    let dirs = metadata_cache.get_remote(http_client, &mirrors_url, |html| {
+        Ok(vec![parse_pool_dir(&mirrors_url, html)?])
+    })?;
+
+    dirs.into_iter()
+        .next()
+        .ok_or_else(|| Error::parse(&mirrors_url, "unexpected metadata cache entry"))
+}
+
+/// Get all versions of a package from remote API
+pub fn get_remote_package_versions(
+    package_name: &str,
+    http_client: &HttpClient,
+    metadata_cache: &mut MetadataCache,
+    apt_env: &AptEnv,
+) -> Result<Vec<Package>, Error> {
+    // Notes:
+    // * using directly index like http://ftp.debian.org/debian/pool/main/libr/libreoffice/
+    // is not reliable because directory is sometimes hard to deduce from package (ie. libasound2 is in alsa-lib dir)
+    // * the API at https://sources.debian.org/doc/api/ is incomplete so useless for our needs
+
+    // Get pool directory
+    let pool_dir = get_package_pool_dir(package_name, http_client, metadata_cache, apt_env)?;
+
+    // Get all .deb filenames of the index, they are shared by all binary packages of the same source,
+    // from the first mirror that works
+    let mut index = Err(Error::Other("No mirror configured".to_string()));
+    for mirror in &apt_env.mirrors {
+        let index_url = format!("{}{}", mirror, pool_dir);
+        index = metadata_cache
+            .get_remote(http_client, &index_url, |html| {
+                Ok(parse_index_filenames(html))
+            })
+            .map(|filenames| (index_url, filenames));
+        match &index {
+            Ok(_) => break,
+            Err(e) => warn!("Failed to get index from mirror {}: {}", mirror, e),
+        }
+    }
+    let (index_url, filenames) = index?;
+
+    Ok(parse_remote_package_versions(
+        package_name,
+        &index_url,
+        &filenames,
+        apt_env,
+    )?)
+}
+
+/// Build apt install command line for a list of packages, also removing some installed packages
+pub fn build_install_cmdline(
+    packages: Vec<Package>,
+    to_remove: &[String],
+    apt_env: &AptEnv,
+) -> Vec<String> {
+    let mut cmd = vec!["apt-get".to_string()];
+    if let Some(root) = apt_env.root.as_deref() {
+        // APT reads its configuration & state from the root, and dpkg installs into it
+        cmd.extend(get_root_apt_options(Some(root)));
+        cmd.push("-o".to_string());
+        cmd.push(format!("DPkg::Options::=--root={}", root.to_string_lossy()));
+    }
+    cmd.extend(vec!["install".to_string(), "-V".to_string()]);
+    cmd.push(if apt_env.install_recommends {
+        "--install-recommends".to_string()
+    } else {
+        "--no-install-recommends".to_string()
+    });
+    if apt_env.install_suggests {
+        cmd.push("--install-suggests".to_string());
+    }
     cmd.extend(
         packages
             .iter()
             .map(|p| p.filepath.as_ref().unwrap().clone()),
     );
+    // A '-' suffix makes apt-get remove the package instead
+    cmd.extend(to_remove.iter().map(|n| format!("{}-", n)));
     cmd
 }
 
+/// Make install command line not ask for confirmation, when the user already confirmed
+pub fn set_assume_yes(cmdline: &mut Vec<String>) {
+    let install_index = cmdline.iter().position(|a| a == "install").unwrap();
+    cmdline.insert(install_index + 1, "--allow-downgrades".to_string());
+    cmdline.insert(install_index + 1, "-y".to_string());
+}
+
+/// Run install command line, with the terminal attached so that the user can confirm
+pub fn run_install_cmdline(cmdline: &[String], runner: &dyn CommandRunner) -> Result<(), Error> {
+    let args: Vec<&str> = cmdline[1..].iter().map(String::as_str).collect();
+    let status = runner.run_attached(&cmdline[0], &args)?;
+    if !status.success() {
+        return Err(Error::Command(CommandError {
+            status,
+            cmd: cmdline.to_vec(),
+            output: String::new(),
+        }));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metadata::DEFAULT_TTL;
+    use crate::runner::{is_in_path, ReplayRunner, SystemRunner};
 
     #[test]
     fn test_build_install_cmdline() {
@@ -643,8 +1299,9 @@ mod tests {
                 url: None,
             },
         ];
+        let mut apt_env = AptEnv::for_tests();
         assert_eq!(
-            build_install_cmdline(packages),
+            build_install_cmdline(packages.clone(), &[], &apt_env),
             vec![
                 "apt-get",
                 "install",
@@ -654,6 +1311,43 @@ mod tests {
                 "/p2"
             ]
         );
+        apt_env.root = Some(PathBuf::from("/srv/rootfs"));
+        assert_eq!(
+            build_install_cmdline(packages.clone(), &["package3".to_string()], &apt_env),
+            vec![
+                "apt-get",
+                "-o",
+                "Dir=/srv/rootfs",
+                "-o",
+                "DPkg::Options::=--root=/srv/rootfs",
+                "install",
+                "-V",
+                "--no-install-recommends",
+                "/p1",
+                "/p2",
+                "package3-"
+            ]
+        );
+
+        let mut apt_env = AptEnv::for_tests();
+        apt_env.install_recommends = true;
+        apt_env.install_suggests = true;
+        let mut cmdline = build_install_cmdline(packages, &[], &apt_env);
+        set_assume_yes(&mut cmdline);
+        assert_eq!(
+            cmdline,
+            vec![
+                "apt-get",
+                "install",
+                "-y",
+                "--allow-downgrades",
+                "-V",
+                "--install-recommends",
+                "--install-suggests",
+                "/p1",
+                "/p2"
+            ]
+        );
     }
 
     #[test]
@@ -867,49 +1561,725 @@ mod tests {
             resolve_dependency(&dependency, candidates.clone(), &installed_package),
             Some(candidates[0].clone())
         );
+
+        //
+        // Installed package not in APT archive cache
+        //
+
+        let remote_candidates: Vec<Package> = candidates
+            .iter()
+            .map(|p| Package {
+                arch: Some("amd64".to_string()),
+                url: Some(format!(
+                    "http://ftp.debian.org/debian/pool/main/p/p1/p1_{}_amd64.deb",
+                    p.version
+                )),
+                ..p.clone()
+            })
+            .collect();
+        let installed = Package {
+            arch: Some("amd64".to_string()),
+            filepath: Some("/var/cache/apt/archives/p1_1.0.1_amd64.deb".to_string()),
+            ..candidates[2].clone()
+        };
+        let installed_package = Some(installed.clone());
+        let dependency: PackageDependency = "p1>=1.0.0".parse().unwrap();
+        assert_eq!(
+            resolve_dependency(&dependency, remote_candidates.clone(), &installed_package),
+            Some(remote_candidates[2].clone())
+        );
+        assert_eq!(
+            resolve_dependency(
+                &dependency,
+                remote_candidates[..2].to_vec(),
+                &installed_package
+            ),
+            installed_package
+        );
+        let installed_package = Some(Package {
+            arch: Some("i386".to_string()),
+            ..installed
+        });
+        assert_eq!(
+            resolve_dependency(&dependency, remote_candidates, &installed_package),
+            installed_package
+        );
+    }
+
+    #[test]
+    fn test_source_version() {
+        for (version, source_version) in &[
+            ("22.3.6-1", "22.3.6-1"),
+            ("22.3.6-1+b1", "22.3.6-1"),
+            ("1:2.0-3+b12", "1:2.0-3"),
+            ("2.0+dfsg-1", "2.0+dfsg-1"),
+            ("2.0-1+bpo12", "2.0-1+bpo12"),
+        ] {
+            assert_eq!(
+                PackageVersion {
+                    string: version.to_string()
+                }
+                .source_version()
+                .string,
+                *source_version
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_package_dependency() {
+        let dependency: PackageDependency = "firefox-esr=68.4.1esr-1".parse().unwrap();
+        assert_eq!(dependency.package_name, "firefox-esr");
+        assert_eq!(dependency.version_constraints.len(), 1);
+        assert_eq!(
+            dependency.version_constraints[0].version.string,
+            "68.4.1esr-1"
+        );
+        assert!(matches!(
+            dependency.version_constraints[0].version_relation,
+            PackageVersionRelation::Equal
+        ));
+
+        let dependency: PackageDependency = "libc6<<2.30".parse().unwrap();
+        assert_eq!(dependency.package_name, "libc6");
+        assert_eq!(dependency.version_constraints[0].version.string, "2.30");
+        assert!(matches!(
+            dependency.version_constraints[0].version_relation,
+            PackageVersionRelation::StrictlyInferior
+        ));
+
+        let dependency: PackageDependency = "g++>=4:9.2.1-3".parse().unwrap();
+        assert_eq!(dependency.package_name, "g++");
+        assert_eq!(dependency.version_constraints[0].version.string, "9.2.1-3");
+        assert!(matches!(
+            dependency.version_constraints[0].version_relation,
+            PackageVersionRelation::SuperiorOrEqual
+        ));
+
+        let dependency: PackageDependency = "firefox-esr=68.*".parse().unwrap();
+        assert_eq!(dependency.version_constraints[0].version.string, "68.*");
+        assert!(matches!(
+            dependency.version_constraints[0].version_relation,
+            PackageVersionRelation::EqualWildcard
+        ));
+
+        let dependency: PackageDependency = "libc6".parse().unwrap();
+        assert_eq!(dependency.package_name, "libc6");
+        assert!(matches!(
+            dependency.version_constraints[0].version_relation,
+            PackageVersionRelation::Any
+        ));
+
+        assert!("".parse::<PackageDependency>().is_err());
+        assert!("libc6<<2.*".parse::<PackageDependency>().is_err());
+        assert!("=2.30".parse::<PackageDependency>().is_err());
+        assert!("libc6=".parse::<PackageDependency>().is_err());
+        assert!("libc6=<2.30".parse::<PackageDependency>().is_err());
+    }
+
+    #[test]
+    fn test_is_satisfied_by() {
+        let package = Package {
+            name: "p1".to_string(),
+            version: PackageVersion {
+                string: "1.0.2".to_string(),
+            },
+            arch: None,
+            filepath: None,
+            url: None,
+        };
+
+        let dependency: PackageDependency = "p1<<1.0.3".parse().unwrap();
+        assert!(dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p1>>1.0.2".parse().unwrap();
+        assert!(!dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p2=1.0.2".parse().unwrap();
+        assert!(!dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p1=1.0.*".parse().unwrap();
+        assert!(dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p1=1.*".parse().unwrap();
+        assert!(dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p1=1.1.*".parse().unwrap();
+        assert!(!dependency.is_satisfied_by(&package));
+        let dependency: PackageDependency = "p1<=0.9.9".parse().unwrap();
+        assert!(!dependency.is_satisfied_by(&package));
     }
 
     #[test]
-    fn test_get_remote_package_versions() {
+    fn test_parse_repo_package_versions() {
         let apt_env = AptEnv {
-            arch: "amd64".to_string(),
             cache_dir: "/tmp".to_string(),
+            ..AptEnv::for_tests()
         };
-        let mut html_cache: HashMap<String, String> = HashMap::new();
-        let r = get_remote_package_versions("libreoffice", &mut html_cache, &apt_env);
-        assert!(r.is_ok());
-        let packages = r.unwrap();
-        assert!(packages.len() > 1);
-        for package in packages {
-            assert_eq!(package.name, "libreoffice");
-            assert!(package.arch.is_some());
-            assert!(package.filepath.is_none());
-            assert!(package.url.is_some());
-            let url = package.url.unwrap();
-            assert!(url.starts_with("http"));
-            assert!(url.ends_with(".deb"));
+        let policy_output = "coreutils:
+  Installed: 9.1-1
+  Candidate: 9.1-1
+  Version table:
+ *** 9.1-1 500
+        100 /var/lib/dpkg/status
+        500 http://deb.debian.org/debian bookworm/main amd64 Packages
+     8.32-4+b1 100
+        100 file:/srv/mirror bullseye/main amd64 Packages
+";
+        let show_output = "Package: coreutils
+Version: 9.1-1
+Architecture: amd64
+Filename: pool/main/c/coreutils/coreutils_9.1-1_amd64.deb
+Size: 2896560
+
+Package: coreutils
+Version: 8.32-4+b1
+Architecture: amd64
+Filename: pool/main/c/coreutils/coreutils_8.32-4+b1_amd64.deb
+Size: 2869860
+
+Package: coreutils
+Version: 8.30-3
+Architecture: amd64
+Description: GNU core utilities
+
+";
+        let packages =
+            parse_repo_package_versions("coreutils", policy_output, show_output, &apt_env);
+        assert_eq!(
+            packages,
+            vec![
+                Package {
+                    name: "coreutils".to_string(),
+                    version: PackageVersion {
+                        string: "9.1-1".to_string(),
+                    },
+                    arch: Some("amd64".to_string()),
+                    filepath: None,
+                    url: Some(
+                        "http://deb.debian.org/debian/pool/main/c/coreutils/coreutils_9.1-1_amd64.deb"
+                            .to_string()
+                    ),
+                },
+                Package {
+                    name: "coreutils".to_string(),
+                    version: PackageVersion {
+                        string: "8.32-4+b1".to_string(),
+                    },
+                    arch: Some("amd64".to_string()),
+                    filepath: Some(
+                        "/srv/mirror/pool/main/c/coreutils/coreutils_8.32-4+b1_amd64.deb"
+                            .to_string()
+                    ),
+                    url: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_installed_version() {
+        let apt_env = AptEnv::for_tests();
+        let dpkg_status = DpkgStatus::parse(include_str!("../test_data/dpkg/status")).unwrap();
+
+        assert_eq!(
+            get_installed_version("libssl3", &dpkg_status, &apt_env),
+            Some(Package {
+                name: "libssl3".to_string(),
+                version: PackageVersion {
+                    string: "3.0.19-1~deb12u2".to_string(),
+                },
+                arch: Some("amd64".to_string()),
+                filepath: Some(
+                    "/var/cache/apt/archives/libssl3_3.0.19-1~deb12u2_amd64.deb".to_string()
+                ),
+                url: None,
+            })
+        );
+
+        // Epoch
+        let package = get_installed_version("vim-common", &dpkg_status, &apt_env).unwrap();
+        assert_eq!(package.version.string, "9.0.1378-2+deb12u2");
+        assert_eq!(package.arch, Some("all".to_string()));
+        assert_eq!(
+            package.filepath,
+            Some("/var/cache/apt/archives/vim-common_2%3a9.0.1378-2+deb12u2_all.deb".to_string())
+        );
+
+        // Not installed
+        assert_eq!(
+            get_installed_version("cowsay", &dpkg_status, &apt_env),
+            None
+        );
+        assert_eq!(get_installed_version("p1", &dpkg_status, &apt_env), None);
+    }
+
+    #[test]
+    fn test_parse_dependencies() {
+        assert_eq!(
+            parse_dependencies(include_str!("../test_data/apt-cache/show_curl.txt"))
+                .unwrap()
+                .depends
+                .iter()
+                .map(PackageDependency::to_string)
+                .collect::<Vec<String>>(),
+            vec![
+                "libc6>=2.34",
+                "libcurl4=7.88.1-10+deb12u14",
+                "zlib1g>=1.1.4"
+            ]
+        );
+
+        // Alternatives, only the first one is used
+        let deps = parse_dependencies(include_str!("../test_data/apt-cache/show_apt.txt")).unwrap();
+        assert_eq!(deps.depends.len(), 10);
+        assert_eq!(deps.depends[0].to_string(), "adduser");
+        assert_eq!(deps.depends[1].to_string(), "gpgv");
+        assert_eq!(deps.depends[9].to_string(), "libsystemd0");
+
+        // Weak dependencies, with all alternatives
+        assert_eq!(deps.recommends.len(), 1);
+        assert_eq!(deps.recommends[0][0].to_string(), "ca-certificates");
+        assert_eq!(deps.suggests.len(), 5);
+        assert_eq!(
+            deps.suggests[1]
+                .iter()
+                .map(PackageDependency::to_string)
+                .collect::<Vec<String>>(),
+            vec!["aptitude", "synaptic", "wajig"]
+        );
+        assert_eq!(deps.suggests[2][0].to_string(), "dpkg-dev>=1.17.2");
+
+        // No dependencies
+        let deps = parse_dependencies("Package: p1\nVersion: 1.0\n").unwrap();
+        assert!(deps.depends.is_empty());
+        assert!(deps.recommends.is_empty());
+        assert!(parse_dependencies("Depends: p2 (~ 1.0)\n").is_err());
+
+        // All alternatives, with architecture qualifiers
+        let deps = parse_dependency_field("p1 (>= 1:1.0) | p2, python3:any", "test").unwrap();
+        assert_eq!(
+            deps.iter()
+                .map(|g| g.iter().map(PackageDependency::to_string).collect())
+                .collect::<Vec<Vec<String>>>(),
+            vec![vec!["p1>=1.0", "p2"], vec!["python3"]]
+        );
+        assert!(parse_dependency_field("", "test").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_dependencies() {
+        let apt_env = AptEnv::for_tests();
+        let http_client = HttpClient::new(&apt_env.network).unwrap();
+        let mut metadata_cache = MetadataCache::new(DEFAULT_TTL, false);
+        let runner = ReplayRunner::default()
+            .record(
+                &["apt-cache", "show", "curl=7.88.1-10+deb12u14"],
+                0,
+                include_str!("../test_data/apt-cache/show_curl.txt"),
+            )
+            .record(&["apt-cache", "show", "curl=1.0"], 100, "");
+        let mut package = Package {
+            name: "curl".to_string(),
+            version: PackageVersion {
+                string: "7.88.1-10+deb12u14".to_string(),
+            },
+            arch: Some("amd64".to_string()),
+            filepath: Some("/nonexistent/curl_7.88.1-10+deb12u14_amd64.deb".to_string()),
+            url: None,
+        };
+
+        let deps = get_dependencies(
+            &mut package,
+            &runner,
+            &http_client,
+            &mut metadata_cache,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(deps.depends.len(), 3);
+        assert!(deps.recommends.is_empty());
+
+        package.version.string = "1.0".to_string();
+        match get_dependencies(
+            &mut package,
+            &runner,
+            &http_client,
+            &mut metadata_cache,
+            &apt_env,
+        ) {
+            Err(Error::Command(e)) => assert_eq!(e.cmd, vec!["apt-cache", "show", "curl=1.0"]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_get_repo_package_versions() {
+        let apt_env = AptEnv::for_tests();
+        let runner = ReplayRunner::default()
+            .record(
+                &["apt-cache", "policy", "libssl3"],
+                0,
+                include_str!("../test_data/apt-cache/policy_libssl3.txt"),
+            )
+            .record(
+                &["apt-cache", "show", "libssl3"],
+                0,
+                include_str!("../test_data/apt-cache/show_libssl3.txt"),
+            );
+
+        let packages = get_repo_package_versions("libssl3", &runner, &apt_env).unwrap();
+        assert_eq!(
+            packages
+                .iter()
+                .map(|p| (p.version.string.as_str(), p.url.as_deref().unwrap()))
+                .collect::<Vec<(&str, &str)>>(),
+            vec![
+                (
+                    "3.0.20-1~deb12u1",
+                    "http://deb.debian.org/debian/pool/main/o/openssl/libssl3_3.0.20-1~deb12u1_amd64.deb"
+                ),
+                (
+                    "3.0.19-1~deb12u2",
+                    "http://deb.debian.org/debian-security/pool/updates/main/o/openssl/libssl3_3.0.19-1~deb12u2_amd64.deb"
+                ),
+                (
+                    "3.0.17-1~deb12u2",
+                    "http://deb.debian.org/debian/pool/main/o/openssl/libssl3_3.0.17-1~deb12u2_amd64.deb"
+                ),
+            ]
+        );
+
+        assert!(get_repo_package_versions("p1", &runner, &apt_env).is_err());
+    }
+
+    #[test]
+    fn test_parse_apt_env() {
+        let values = parse_apt_config_shell(
+            "CACHE_DIR='/var/cache/apt/archives/'
+ARCH='amd64'
+HTTP_PROXY='http://proxy:3128/'
+HTTPS_PROXY='DIRECT'
+RETRIES='5'
+DPKG_STATUS='/srv/rootfs/var/lib/dpkg/status'
+",
+        );
+        let apt_env = parse_apt_env(&values).unwrap();
+        assert_eq!(apt_env.arch, "amd64");
+        assert_eq!(apt_env.cache_dir, "/var/cache/apt/archives/");
+        assert_eq!(
+            apt_env.dpkg_status,
+            PathBuf::from("/srv/rootfs/var/lib/dpkg/status")
+        );
+        assert_eq!(
+            apt_env.network,
+            NetworkConfig {
+                http_proxy: Some("http://proxy:3128/".to_string()),
+                https_proxy: None,
+                retries: 5,
+                timeout: Duration::from_secs(120),
+            }
+        );
+
+        let values = parse_apt_config_shell(
+            "CACHE_DIR='/var/cache/apt/archives/'
+ARCH='arm64'
+",
+        );
+        let apt_env = parse_apt_env(&values).unwrap();
+        assert_eq!(apt_env.network, NetworkConfig::default());
+        assert_eq!(apt_env.dpkg_status, PathBuf::from(DEFAULT_STATUS_PATH));
+
+        assert!(parse_apt_env(&parse_apt_config_shell("ARCH='amd64'\n")).is_err());
+    }
+
+    /// Copy a directory tree
+    fn copy_dir(src: &Path, dst: &Path) {
+        fs::create_dir_all(dst).unwrap();
+        for entry in fs::read_dir(src).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &dst.join(entry.file_name()));
+            } else {
+                fs::copy(entry.path(), dst.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_root() {
+        if !is_in_path("apt-config") || !is_in_path("apt-cache") {
+            eprintln!("APT tools not found, skipping test");
+            return;
+        }
+
+        // Work on a copy, so that APT does not write its binary caches in the test data directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().join("root");
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test_data")
+                .join("root"),
+            &root,
+        );
+        let apt_env = read_apt_env(&SystemRunner, Some(&root)).unwrap();
+        assert_eq!(
+            apt_env.cache_dir,
+            format!("{}/var/cache/apt/archives/", root.to_str().unwrap())
+        );
+        assert_eq!(apt_env.dpkg_status, root.join("var/lib/dpkg/status"));
+        assert_eq!(apt_env.mirrors, vec![DEFAULT_MIRROR.to_string()]);
+        assert_eq!(apt_env.root, Some(root.clone()));
+
+        // Installed package from the root dpkg database, not the live system one
+        let dpkg_status = DpkgStatus::load(&apt_env.dpkg_status).unwrap();
+        let installed = get_installed_version("libssl3", &dpkg_status, &apt_env).unwrap();
+        assert_eq!(installed.version.string, "3.0.19-1~deb12u2");
+        assert_eq!(
+            get_installed_version("vim-common", &dpkg_status, &apt_env),
+            None
+        );
+
+        // Versions from the root APT sources
+        let packages = get_repo_package_versions("libssl3", &SystemRunner, &apt_env).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].version.string, "3.0.11-1~deb12u2");
+        assert_eq!(
+            packages[0].filepath,
+            Some("/srv/mirror/pool/main/o/openssl/libssl3_3.0.11-1~deb12u2_amd64.deb".to_string())
+        );
+        assert_eq!(
+            get_repo_package_sha256(
+                "libssl3",
+                "3.0.11-1~deb12u2",
+                "amd64",
+                &SystemRunner,
+                &apt_env
+            )
+            .unwrap(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_mirrors() {
+        assert_eq!(
+            parse_mirrors(
+                "http://deb.debian.org/debian-security/
+http://deb.debian.org/debian/
+https://deb.nodesource.com/node_20.x/
+http://deb.debian.org/debian
+http://localhost:3142/debian
+"
+            ),
+            vec![
+                "http://deb.debian.org/debian/".to_string(),
+                "http://localhost:3142/debian/".to_string()
+            ]
+        );
+        assert!(parse_mirrors("").is_empty());
+    }
+
+    #[test]
+    fn test_get_mirror_urls() {
+        let mirrors = vec![
+            "http://localhost:3142/debian/".to_string(),
+            "http://deb.debian.org/debian/".to_string(),
+        ];
+        assert_eq!(
+            get_mirror_urls(
+                "http://deb.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb",
+                &mirrors
+            ),
+            vec![
+                "http://localhost:3142/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb"
+                    .to_string(),
+                "http://deb.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb"
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            get_mirror_urls(
+                "http://ftp.fr.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb",
+                &mirrors
+            ),
+            vec![
+                "http://localhost:3142/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb"
+                    .to_string(),
+                "http://deb.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb"
+                    .to_string(),
+                "http://ftp.fr.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1_amd64.deb"
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            get_mirror_urls("http://example.com/p1_1.0_all.deb", &mirrors),
+            vec!["http://example.com/p1_1.0_all.deb".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_scan_deb_dirs() {
+        let apt_env = AptEnv {
+            cache_dir: "/tmp".to_string(),
+            ..AptEnv::for_tests()
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let deb_dir = tmp_dir.path().join("debs");
+        fs::create_dir_all(deb_dir.join("sub")).unwrap();
+        let mut runner = ReplayRunner::default();
+        for (name, version, arch, deb_filepath) in &[
+            // filename does not match actual package
+            (
+                "p1",
+                "1:1.2.3-1",
+                "amd64",
+                deb_dir.join("sub").join("p1_9.9_i386.deb"),
+            ),
+            ("p2", "2.0", "all", deb_dir.join("p2.deb")),
+            ("p3", "3.0", "arm64", deb_dir.join("p3_3.0_arm64.deb")),
+        ] {
+            fs::write(deb_filepath, "").unwrap();
+            runner = runner.record(
+                &[
+                    "dpkg-deb",
+                    "--field",
+                    deb_filepath.to_str().unwrap(),
+                    "Package",
+                    "Version",
+                    "Architecture",
+                ],
+                0,
+                &format!(
+                    "Package: {}\nVersion: {}\nArchitecture: {}\n",
+                    name, version, arch
+                ),
+            );
         }
+
+        let mut metadata_cache = MetadataCache::new(DEFAULT_TTL, false);
+        let packages = scan_deb_dirs(
+            std::slice::from_ref(&deb_dir),
+            &runner,
+            &mut metadata_cache,
+            &apt_env,
+        );
+        assert_eq!(packages.len(), 2);
+        assert_eq!(
+            packages["p1"],
+            vec![Package {
+                name: "p1".to_string(),
+                version: PackageVersion {
+                    string: "1.2.3-1".to_string(),
+                },
+                arch: Some("amd64".to_string()),
+                filepath: Some(
+                    deb_dir
+                        .join("sub")
+                        .join("p1_9.9_i386.deb")
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                ),
+                url: None,
+            }]
+        );
+        assert_eq!(packages["p2"][0].version.string, "2.0");
+        assert_eq!(packages["p2"][0].arch, Some("all".to_string()));
+    }
+
+    #[test]
+    fn test_parse_pool_dir() {
+        let url = "https://packages.debian.org/sid/amd64/libasound2/download";
+        assert_eq!(
+            parse_pool_dir(
+                url,
+                include_str!("../test_data/packages.debian.org/download_libasound2.html")
+            )
+            .unwrap(),
+            "pool/main/a/alsa-lib/"
+        );
+
+        let url = "https://packages.debian.org/sid/amd64/nonexistent-package/download";
+        let err = parse_pool_dir(
+            url,
+            include_str!("../test_data/packages.debian.org/download_no_link.html"),
+        )
+        .unwrap_err();
+        assert_eq!(err.url, url);
+        assert_eq!(err.reason, "no download link");
+        assert!(err.to_string().contains(url));
     }
 
     #[test]
-    fn test_get_package_index_url() {
+    fn test_parse_remote_package_versions() {
         let apt_env = AptEnv {
-            arch: "amd64".to_string(),
             cache_dir: "/tmp".to_string(),
+            ..AptEnv::for_tests()
         };
+        let index_url = "http://deb.debian.org/debian/pool/main/a/alsa-lib/";
+
+        let filenames = parse_index_filenames(include_str!("../test_data/pool/alsa-lib.html"));
+        assert_eq!(filenames.len(), 8);
+        assert!(filenames.iter().all(|f| f.ends_with(".deb")));
+        assert!(filenames.contains(&"libasound2_1.2.8-1+b1_amd64.deb".to_string()));
 
-        let r = get_package_index_url("libreoffice", &apt_env);
-        assert!(r.is_ok());
+        let packages =
+            parse_remote_package_versions("libasound2", index_url, &filenames, &apt_env).unwrap();
         assert_eq!(
-            r.unwrap(),
-            "http://ftp.debian.org/debian/pool/main/libr/libreoffice/"
+            packages,
+            vec![
+                Package {
+                    name: "libasound2".to_string(),
+                    version: PackageVersion {
+                        string: "1.2.4-1.1".to_string(),
+                    },
+                    arch: Some("amd64".to_string()),
+                    filepath: None,
+                    url: Some(
+                        "http://deb.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.4-1.1_amd64.deb"
+                            .to_string()
+                    ),
+                },
+                Package {
+                    name: "libasound2".to_string(),
+                    version: PackageVersion {
+                        string: "1.2.8-1+b1".to_string(),
+                    },
+                    arch: Some("amd64".to_string()),
+                    filepath: None,
+                    url: Some(
+                        "http://deb.debian.org/debian/pool/main/a/alsa-lib/libasound2_1.2.8-1+b1_amd64.deb"
+                            .to_string()
+                    ),
+                },
+            ]
+        );
+
+        let packages =
+            parse_remote_package_versions("libasound2-data", index_url, &filenames, &apt_env)
+                .unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].arch, Some("all".to_string()));
+
+        // udebs are never candidates
+        assert!(
+            parse_remote_package_versions("libasound2-udeb", index_url, &filenames, &apt_env)
+                .unwrap()
+                .is_empty()
         );
 
-        let r = get_package_index_url("libasound2", &apt_env);
-        assert!(r.is_ok());
+        let filenames = parse_index_filenames(include_str!("../test_data/pool/malformed.html"));
+        let err = parse_remote_package_versions("libasound2", index_url, &filenames, &apt_env)
+            .unwrap_err();
+        assert_eq!(err.url, index_url);
+        assert_eq!(err.element, "a[href=\"libasound2_1.2.8-1.deb\"]");
+    }
+
+    #[test]
+    fn test_parse_index_filenames_percent_encoded() {
         assert_eq!(
-            r.unwrap(),
-            "http://ftp.debian.org/debian/pool/main/a/alsa-lib/"
+            parse_index_filenames(
+                "<a href=\"p_1.0-1%2bb1_amd64.deb\">p_1.0-1+b1_amd64.deb</a>\
+                 <a href=\"p_1%3a1.0_all.deb\">p_1:1.0_all.deb</a>\
+                 <a href=\"p_100%_all.deb\">p_100%_all.deb</a>"
+            ),
+            vec!["p_1.0-1+b1_amd64.deb", "p_1:1.0_all.deb", "p_100%_all.deb"]
         );
     }
 }
@@ -0,0 +1,289 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::Error;
+
+/// A .deb file in a package cache directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedPackage {
+    pub filepath: PathBuf,
+
+    pub filename: String,
+
+    /// File size in bytes
+    pub size: u64,
+
+    /// File modification time
+    pub mtime: SystemTime,
+}
+
+impl CachedPackage {
+    /// Get package name, version (with epoch) and architecture from filename
+    pub fn parse_filename(&self) -> Option<(String, String, String)> {
+        let stem = self.filename.strip_suffix(".deb")?;
+        let mut tokens = stem.splitn(3, '_');
+        let name = tokens.next()?;
+        let version = tokens.next()?.replace("%3a", ":");
+        let arch = tokens.next()?;
+        Some((name.to_string(), version, arch.to_string()))
+    }
+
+    /// Get time elapsed since file was last modified
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.mtime).unwrap_or_default()
+    }
+}
+
+/// List files in a cache directory whose name ends with an extension, sorted by filename
+fn list_files(dir: &Path, ext: &str) -> Result<Vec<CachedPackage>, Error> {
+    let mut packages = Vec::new();
+    if !dir.is_dir() {
+        return Ok(packages);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if !filename.ends_with(ext) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        packages.push(CachedPackage {
+            filepath: entry.path(),
+            filename,
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+        });
+    }
+    packages.sort_by(|a, b| a.filename.cmp(&b.filename));
+    Ok(packages)
+}
+
+/// List .deb files in a cache directory, sorted by filename
+pub fn list_packages(dir: &Path) -> Result<Vec<CachedPackage>, Error> {
+    list_files(dir, ".deb")
+}
+
+/// Remove leftover files of interrupted downloads, and return their paths
+pub fn remove_tmp_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut removed = Vec::new();
+    for tmp_file in list_files(dir, ".tmp")? {
+        debug!("Removing {:?}", tmp_file.filepath);
+        fs::remove_file(&tmp_file.filepath)?;
+        removed.push(tmp_file.filepath);
+    }
+    Ok(removed)
+}
+
+/// Select packages to remove so that none is older than max_age, and total size does not exceed max_size,
+/// removing oldest packages first
+pub fn select_prune(
+    packages: &[CachedPackage],
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    now: SystemTime,
+) -> Vec<CachedPackage> {
+    let mut by_age: Vec<&CachedPackage> = packages.iter().collect();
+    by_age.sort_by_key(|p| p.mtime);
+
+    let mut total_size: u64 = packages.iter().map(|p| p.size).sum();
+    let mut to_remove = Vec::new();
+    for package in by_age {
+        let too_old = match max_age {
+            Some(max_age) => package.age(now) > max_age,
+            None => false,
+        };
+        let too_big = match max_size {
+            Some(max_size) => total_size > max_size,
+            None => false,
+        };
+        if !too_old && !too_big {
+            continue;
+        }
+        total_size -= package.size;
+        to_remove.push(package.clone());
+    }
+    to_remove
+}
+
+/// Copy .deb files missing from a directory to another, and return the paths of copied files
+pub fn copy_packages(src_dir: &Path, dst_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut copied = Vec::new();
+    fs::create_dir_all(dst_dir)?;
+    for package in list_packages(src_dir)? {
+        let dst_filepath = dst_dir.join(&package.filename);
+        if dst_filepath.exists() {
+            continue;
+        }
+        debug!("Copying {:?} to {:?}", package.filepath, dst_filepath);
+        let mut dst_filepath_tmp = dst_filepath.clone().into_os_string();
+        dst_filepath_tmp.push(".tmp");
+        fs::copy(&package.filepath, &dst_filepath_tmp)?;
+        fs::rename(&dst_filepath_tmp, &dst_filepath)?;
+        copied.push(dst_filepath);
+    }
+    Ok(copied)
+}
+
+/// Parse a size like '500M' or '2G' to a number of bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size {:?}", s))
+}
+
+/// Parse an age in days to a duration
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    s.parse::<u64>()
+        .ok()
+        .and_then(|d| d.checked_mul(24 * 60 * 60))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Invalid number of days {:?}", s))
+}
+
+/// Format a number of bytes for humans
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_package(filename: &str, size: u64, age_days: u64, now: SystemTime) -> CachedPackage {
+        CachedPackage {
+            filepath: PathBuf::from("/nonexistent").join(filename),
+            filename: filename.to_string(),
+            size,
+            mtime: now - Duration::from_secs(age_days * 24 * 60 * 60),
+        }
+    }
+
+    #[test]
+    fn test_parse_filename() {
+        let now = SystemTime::now();
+        assert_eq!(
+            cached_package("libc6_2.28-10_amd64.deb", 0, 0, now).parse_filename(),
+            Some((
+                "libc6".to_string(),
+                "2.28-10".to_string(),
+                "amd64".to_string()
+            ))
+        );
+        assert_eq!(
+            cached_package("vim_2%3a8.1.2269-1_amd64.deb", 0, 0, now).parse_filename(),
+            Some((
+                "vim".to_string(),
+                "2:8.1.2269-1".to_string(),
+                "amd64".to_string()
+            ))
+        );
+        assert_eq!(
+            cached_package("garbage.deb", 0, 0, now).parse_filename(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_select_prune() {
+        let now = SystemTime::now();
+        let packages = vec![
+            cached_package("a_1_all.deb", 100, 10, now),
+            cached_package("b_1_all.deb", 200, 30, now),
+            cached_package("c_1_all.deb", 300, 1, now),
+        ];
+
+        assert!(select_prune(&packages, None, None, now).is_empty());
+
+        let names = |to_remove: Vec<CachedPackage>| -> Vec<String> {
+            to_remove.into_iter().map(|p| p.filename).collect()
+        };
+        assert_eq!(
+            names(select_prune(
+                &packages,
+                Some(Duration::from_secs(20 * 24 * 60 * 60)),
+                None,
+                now
+            )),
+            vec!["b_1_all.deb"]
+        );
+        assert_eq!(
+            names(select_prune(&packages, None, Some(350), now)),
+            vec!["b_1_all.deb", "a_1_all.deb"]
+        );
+        assert_eq!(
+            names(select_prune(&packages, None, Some(600), now)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_tmp_and_copy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let src_dir = tmp_dir.path().join("src");
+        let dst_dir = tmp_dir.path().join("dst");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(src_dir.join("a_1_all.deb"), "a").unwrap();
+        fs::write(src_dir.join("b_1_all.deb"), "b").unwrap();
+        fs::write(src_dir.join("c_1_all.deb.tmp"), "c").unwrap();
+
+        assert_eq!(list_packages(&src_dir).unwrap().len(), 2);
+        assert_eq!(
+            remove_tmp_files(&src_dir).unwrap(),
+            vec![src_dir.join("c_1_all.deb.tmp")]
+        );
+        assert!(!src_dir.join("c_1_all.deb.tmp").exists());
+
+        fs::create_dir_all(&dst_dir).unwrap();
+        fs::write(dst_dir.join("a_1_all.deb"), "a").unwrap();
+        assert_eq!(
+            copy_packages(&src_dir, &dst_dir).unwrap(),
+            vec![dst_dir.join("b_1_all.deb")]
+        );
+        assert_eq!(
+            fs::read_to_string(dst_dir.join("b_1_all.deb")).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("500M"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("abc").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("0"), Ok(Duration::from_secs(0)));
+        assert_eq!(parse_age("2"), Ok(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert!(parse_age("-1").is_err());
+        assert!(parse_age("999999999999999999").is_err());
+    }
+}
@@ -0,0 +1,475 @@
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+use crate::apt::PackageVersion;
+use crate::error::Error;
+use crate::runner::CommandRunner;
+
+/// Changelog filename in package documentation directories
+const DEBIAN_CHANGELOG_FILENAME: &str = "changelog.Debian.gz";
+
+/// Changelog filename of native packages, for other packages this is the upstream changelog
+const NATIVE_CHANGELOG_FILENAME: &str = "changelog.gz";
+
+/// Entry of a Debian changelog
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangelogEntry {
+    /// Version, without epoch
+    pub version: PackageVersion,
+
+    /// Full entry text, from header line to trailer line
+    pub text: String,
+}
+
+impl ChangelogEntry {
+    /// Get CVE identifiers mentioned in the entry, without duplicates
+    pub fn cve_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = Vec::new();
+        for range in find_cve_id_ranges(&self.text) {
+            let id = &self.text[range];
+            if !ids.iter().any(|i| i == id) {
+                ids.push(id.to_string());
+            }
+        }
+        ids
+    }
+}
+
+/// Find positions of CVE identifiers like 'CVE-2023-5363' in a text
+fn find_cve_id_ranges(text: &str) -> Vec<Range<usize>> {
+    let count_digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let mut ranges = Vec::new();
+    for (start, prefix) in text.match_indices("CVE-") {
+        let rest = &text[start + prefix.len()..];
+        if (count_digits(rest) != 4) || !rest[4..].starts_with('-') {
+            continue;
+        }
+        let number_len = count_digits(&rest[5..]);
+        if number_len < 4 {
+            continue;
+        }
+        ranges.push(start..start + prefix.len() + 5 + number_len);
+    }
+    ranges
+}
+
+/// Surround CVE identifiers of a text, ie. with terminal color escape sequences
+pub fn highlight_cve_ids(text: &str, before: &str, after: &str) -> String {
+    let mut highlighted = String::new();
+    let mut prev_end = 0;
+    for range in find_cve_id_ranges(text) {
+        highlighted.push_str(&text[prev_end..range.start]);
+        highlighted.push_str(before);
+        highlighted.push_str(&text[range.clone()]);
+        highlighted.push_str(after);
+        prev_end = range.end;
+    }
+    highlighted.push_str(&text[prev_end..]);
+    highlighted
+}
+
+/// Parse Debian changelog content, stopping at the first line that is not part of an entry, ie. old changelog
+/// sections
+pub fn parse(content: &str) -> Vec<ChangelogEntry> {
+    let mut entries: Vec<ChangelogEntry> = Vec::new();
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with(' ') {
+            if let Some(entry) = entries.last_mut() {
+                entry.text.push('\n');
+                entry.text.push_str(line);
+            }
+            continue;
+        }
+
+        // Header line, ie. 'openssl (3.0.11-1~deb12u2) bookworm-security; urgency=medium'
+        let version = line
+            .split(" (")
+            .nth(1)
+            .and_then(|s| s.split(')').next())
+            .filter(|_| line.contains(')'));
+        match version {
+            Some(version) => entries.push(ChangelogEntry {
+                version: PackageVersion {
+                    string: version.rsplit(':').next().unwrap().to_string(),
+                },
+                text: line.to_string(),
+            }),
+            None => break,
+        }
+    }
+    for entry in &mut entries {
+        entry.text = entry.text.trim_end().to_string();
+    }
+    entries
+}
+
+/// Get entries for versions after the lowest version and up to the highest one, from both changelogs, most recent
+/// first
+pub fn get_entries_between(
+    changelog1: &[ChangelogEntry],
+    changelog2: &[ChangelogEntry],
+    version1: &PackageVersion,
+    version2: &PackageVersion,
+) -> Vec<ChangelogEntry> {
+    let (lowest_version, highest_version) = if version1 < version2 {
+        (version1, version2)
+    } else {
+        (version2, version1)
+    };
+    let mut entries: Vec<ChangelogEntry> = Vec::new();
+    for entry in changelog1.iter().chain(changelog2) {
+        if (&entry.version > lowest_version)
+            && (&entry.version <= highest_version)
+            && !entries.iter().any(|e| e.version == entry.version)
+        {
+            entries.push(entry.clone());
+        }
+    }
+    entries.sort_by(|a, b| b.version.cmp(&a.version));
+    entries
+}
+
+/// Changelog files of a package, decompressed
+#[derive(Default)]
+struct ChangelogFiles {
+    /// Entries of binary only rebuilds (binNMU), ie. from 'changelog.Debian.amd64.gz'
+    binnmu: Option<String>,
+
+    debian: Option<String>,
+
+    native: Option<String>,
+}
+
+impl ChangelogFiles {
+    /// Get content to fill for a filename, None if it is not a changelog file
+    fn get_mut(&mut self, filename: &str) -> Option<&mut Option<String>> {
+        if filename == DEBIAN_CHANGELOG_FILENAME {
+            Some(&mut self.debian)
+        } else if filename == NATIVE_CHANGELOG_FILENAME {
+            Some(&mut self.native)
+        } else if filename.starts_with("changelog.Debian.") && filename.ends_with(".gz") {
+            Some(&mut self.binnmu)
+        } else {
+            None
+        }
+    }
+
+    /// Get the full changelog, most recent entries first, None if there is none
+    fn merge(self) -> Option<String> {
+        let ChangelogFiles {
+            binnmu,
+            debian,
+            native,
+        } = self;
+        match (binnmu, debian) {
+            (Some(binnmu), Some(debian)) => Some(format!("{}\n{}", binnmu, debian)),
+            (binnmu, debian) => debian.or(binnmu).or(native),
+        }
+    }
+}
+
+/// Decompress a gzip changelog file
+fn decompress(reader: impl Read) -> Result<String, Error> {
+    let mut content = Vec::new();
+    GzDecoder::new(reader).read_to_end(&mut content)?;
+    Ok(String::from_utf8_lossy(&content).to_string())
+}
+
+/// Read changelog of an installed package, None if documentation is not installed
+pub fn read_installed_changelog(
+    package_name: &str,
+    root: Option<&Path>,
+) -> Result<Option<String>, Error> {
+    let doc_dir = root
+        .unwrap_or_else(|| Path::new("/"))
+        .join("usr/share/doc")
+        .join(package_name);
+    if !doc_dir.is_dir() {
+        return Ok(None);
+    }
+
+    let mut files = ChangelogFiles::default();
+    for entry in fs::read_dir(&doc_dir)? {
+        let entry = entry?;
+        if let Some(file) = files.get_mut(&entry.file_name().to_string_lossy()) {
+            debug!("Reading {:?}", entry.path());
+            *file = Some(decompress(File::open(entry.path())?)?);
+        }
+    }
+    Ok(files.merge())
+}
+
+/// Read changelog files of a package from a data archive, ignoring symbolic links because their target may not be in
+/// the archive
+fn read_tar_changelog(
+    reader: &mut dyn Read,
+    package_name: &str,
+    files: &mut ChangelogFiles,
+) -> Result<(), Error> {
+    let doc_dir = Path::new("usr/share/doc").join(package_name);
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let filename = {
+            let path = entry.path()?;
+            let path = path.strip_prefix(".").unwrap_or(&path);
+            match (path.parent(), path.file_name()) {
+                (Some(parent), Some(filename)) if parent == doc_dir => {
+                    filename.to_string_lossy().to_string()
+                }
+                _ => continue,
+            }
+        };
+        if let Some(file) = files.get_mut(&filename) {
+            *file = Some(decompress(entry)?);
+        }
+    }
+    Ok(())
+}
+
+/// Read changelog from a package file, streaming its data archive without extracting it, None if it has none
+pub fn read_deb_changelog(
+    deb_filepath: &Path,
+    package_name: &str,
+    runner: &dyn CommandRunner,
+) -> Result<Option<String>, Error> {
+    let mut files = ChangelogFiles::default();
+    runner.run_streamed(
+        "dpkg-deb",
+        &["--fsys-tarfile", &deb_filepath.to_string_lossy()],
+        &mut |reader| read_tar_changelog(reader, package_name, &mut files),
+    )?;
+    Ok(files.merge())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use crate::runner::{is_in_path, SystemRunner};
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(include_str!("../test_data/changelog/libssl3.txt"));
+        assert_eq!(entries.len(), 14);
+        assert_eq!(entries[0].version.string, "3.0.19-1~deb12u2");
+        assert!(entries[0].text.starts_with(
+            "openssl (3.0.19-1~deb12u2) bookworm-security; urgency=medium\n\n  * CVE-2026-28387"
+        ));
+        assert!(entries[0].text.ends_with("+0200"));
+        assert_eq!(
+            entries[13].text,
+            "openssl (3.0.11-1~deb12u2) bookworm-security; urgency=medium
+
+  * CVE-2023-5363 (Incorrect cipher key and IV length processing).
+
+ -- Sebastian Andrzej Siewior <sebastian@breakpoint.cc>  Mon, 23 Oct 2023 19:52:22 +0200"
+        );
+
+        assert_eq!(
+            parse("p1 (1:1.0-1) unstable; urgency=low\n\n  * Initial release\n")[0]
+                .version
+                .string,
+            "1.0-1"
+        );
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn test_get_entries_between() {
+        let installed_entries = parse(include_str!("../test_data/changelog/libssl3.txt"));
+        let target_entries = installed_entries[13..].to_vec();
+        let installed_version = PackageVersion {
+            string: "3.0.19-1~deb12u2".to_string(),
+        };
+        let target_version = PackageVersion {
+            string: "3.0.11-1~deb12u2".to_string(),
+        };
+
+        let entries = get_entries_between(
+            &installed_entries,
+            &target_entries,
+            &installed_version,
+            &target_version,
+        );
+        assert_eq!(entries.len(), 13);
+        assert_eq!(entries[0].version, installed_version);
+        assert_eq!(entries[12].version.string, "3.0.13-1~deb12u1");
+        assert_eq!(
+            get_entries_between(
+                &target_entries,
+                &installed_entries,
+                &target_version,
+                &installed_version
+            ),
+            entries
+        );
+
+        assert!(get_entries_between(
+            &installed_entries,
+            &target_entries,
+            &installed_version,
+            &installed_version
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_cve_ids() {
+        let entries = parse(include_str!("../test_data/changelog/libssl3.txt"));
+        assert_eq!(
+            entries[0].cve_ids(),
+            vec![
+                "CVE-2026-28387",
+                "CVE-2026-28389",
+                "CVE-2026-28390",
+                "CVE-2026-31789",
+                "CVE-2026-31790"
+            ]
+        );
+        assert!(entries[1].cve_ids().is_empty());
+
+        let entry = ChangelogEntry {
+            version: PackageVersion {
+                string: "1.0".to_string(),
+            },
+            text: "CVE-2024-1234, CVE-2024-12345 and CVE-2024-1234 again, not CVE-24-1234 or CVE-2024-12"
+                .to_string(),
+        };
+        assert_eq!(entry.cve_ids(), vec!["CVE-2024-1234", "CVE-2024-12345"]);
+        assert_eq!(
+            highlight_cve_ids(&entry.text, "<", ">"),
+            "<CVE-2024-1234>, <CVE-2024-12345> and <CVE-2024-1234> again, not CVE-24-1234 or CVE-2024-12"
+        );
+    }
+
+    /// Compress content like gzip
+    fn compress(content: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    const CHANGELOG: &str = "p1 (1.0-1) unstable; urgency=low\n\n  * Initial release\n\n -- Test <test@example.com>  Sat, 01 Feb 2020 12:30:00 +0000\n";
+
+    const BINNMU_CHANGELOG: &str = "p1 (1.0-1+b1) unstable; urgency=low, binary-only=yes\n\n  * Binary-only non-maintainer upload for amd64; no source changes.\n\n -- Test <test@example.com>  Sat, 01 Feb 2020 13:30:00 +0000\n";
+
+    #[test]
+    fn test_read_tar_changelog() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in &[
+            (
+                "./usr/share/doc/p1/changelog.Debian.gz",
+                compress(CHANGELOG),
+            ),
+            (
+                "./usr/share/doc/p1/changelog.Debian.amd64.gz",
+                compress(BINNMU_CHANGELOG),
+            ),
+            ("./usr/share/doc/p1/changelog.gz", compress("upstream")),
+            ("./usr/share/doc/p1/copyright", b"copyright".to_vec()),
+            ("./usr/share/doc/p3/changelog.gz", compress(CHANGELOG)),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, &content[..])
+                .unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "./usr/share/doc/p2", "/usr/share/doc/p1")
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let read = |package_name| {
+            let mut files = ChangelogFiles::default();
+            read_tar_changelog(&mut &archive[..], package_name, &mut files).unwrap();
+            files.merge()
+        };
+        let changelog = read("p1").unwrap();
+        assert_eq!(changelog, format!("{}\n{}", BINNMU_CHANGELOG, CHANGELOG));
+        let entries = parse(&changelog);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].version.string, "1.0-1+b1");
+        assert_eq!(read("p2"), None);
+        assert_eq!(read("p3"), Some(CHANGELOG.to_string()));
+    }
+
+    #[test]
+    fn test_read_installed_changelog() {
+        let root = tempfile::tempdir().unwrap();
+        let doc_dir = root.path().join("usr/share/doc/p1");
+        fs::create_dir_all(&doc_dir).unwrap();
+        fs::write(doc_dir.join("changelog.Debian.gz"), compress(CHANGELOG)).unwrap();
+        fs::write(doc_dir.join("changelog.gz"), compress("upstream")).unwrap();
+
+        assert_eq!(
+            read_installed_changelog("p1", Some(root.path())).unwrap(),
+            Some(CHANGELOG.to_string())
+        );
+        assert_eq!(
+            read_installed_changelog("p2", Some(root.path())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_deb_changelog() {
+        if !is_in_path("dpkg-deb") {
+            eprintln!("dpkg-deb not found, skipping test");
+            return;
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let build_dir = tmp_dir.path().join("build");
+        let doc_dir = build_dir.join("usr/share/doc/p1");
+        fs::create_dir_all(build_dir.join("DEBIAN")).unwrap();
+        fs::create_dir_all(&doc_dir).unwrap();
+        fs::write(
+            build_dir.join("DEBIAN").join("control"),
+            "Package: p1\nVersion: 1.0-1\nArchitecture: all\nMaintainer: Test <test@example.com>\nDescription: Test\n",
+        )
+        .unwrap();
+        fs::write(doc_dir.join("changelog.Debian.gz"), compress(CHANGELOG)).unwrap();
+        let deb_filepath = tmp_dir.path().join("p1.deb");
+        SystemRunner
+            .run_checked(
+                "dpkg-deb",
+                &[
+                    "--build",
+                    "--root-owner-group",
+                    build_dir.to_str().unwrap(),
+                    deb_filepath.to_str().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            read_deb_changelog(&deb_filepath, "p1", &SystemRunner).unwrap(),
+            Some(CHANGELOG.to_string())
+        );
+        assert_eq!(
+            read_deb_changelog(&deb_filepath, "p2", &SystemRunner).unwrap(),
+            None
+        );
+        assert!(read_deb_changelog(&tmp_dir.path().join("p2.deb"), "p2", &SystemRunner).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::metadata;
+
+/// Values read from the configuration file
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Additional directories to search for .deb files
+    pub deb_dirs: Vec<PathBuf>,
+
+    /// Time during which cached remote package metadata is used without revalidation, in seconds
+    pub metadata_cache_ttl: u64,
+
+    /// Base URLs of archive mirrors to download packages from, in order of preference
+    pub mirrors: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            deb_dirs: Vec::new(),
+            metadata_cache_ttl: metadata::DEFAULT_TTL,
+            mirrors: Vec::new(),
+        }
+    }
+}
+
+/// Get configuration filepath
+pub fn get_config_filepath() -> Result<PathBuf, Error> {
+    let dirs = ProjectDirs::from("", "Desbma", "APT Downgrade")
+        .ok_or_else(|| Error::Other("Unable to compute config dir".to_string()))?;
+    Ok(dirs.config_dir().join("config.toml"))
+}
+
+impl Config {
+    /// Read configuration file, or get default configuration if it does not exist
+    pub fn load() -> Result<Config, Error> {
+        let filepath = get_config_filepath()?;
+        if !filepath.is_file() {
+            return Ok(Config::default());
+        }
+        debug!("Reading configuration from {:?}", filepath);
+        let content = fs::read_to_string(&filepath)?;
+        Config::parse(&content).map_err(|e| {
+            Error::parse(
+                &format!("configuration file {:?}", filepath),
+                &e.to_string(),
+            )
+        })
+    }
+
+    /// Parse configuration file content
+    fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("").unwrap();
+        assert!(config.deb_dirs.is_empty());
+        assert_eq!(config.metadata_cache_ttl, metadata::DEFAULT_TTL);
+
+        let config = Config::parse("deb_dirs = [\"/mnt/debs\", \"/media/usb\"]\n").unwrap();
+        assert_eq!(
+            config.deb_dirs,
+            vec![PathBuf::from("/mnt/debs"), PathBuf::from("/media/usb")]
+        );
+
+        let config = Config::parse("metadata_cache_ttl = 3600\n").unwrap();
+        assert_eq!(config.metadata_cache_ttl, 3600);
+
+        let config = Config::parse("mirrors = [\"http://localhost:3142/debian/\"]\n").unwrap();
+        assert_eq!(
+            config.mirrors,
+            vec!["http://localhost:3142/debian/".to_string()]
+        );
+
+        assert!(Config::parse("unknown_key = 1\n").is_err());
+    }
+}
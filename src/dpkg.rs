@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use itertools::join;
+
+use crate::apt::get_control_field;
+use crate::error::Error;
+
+/// Default dpkg status database path
+pub const DEFAULT_STATUS_PATH: &str = "/var/lib/dpkg/status";
+
+/// A package record of the dpkg status database
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstalledPackage {
+    pub name: String,
+
+    /// Exact version, with epoch
+    pub version: String,
+
+    pub arch: String,
+
+    /// Status field, ie. 'install ok installed'
+    pub status: String,
+
+    /// Pre-Depends and Depends fields, comma separated
+    pub depends: String,
+
+    /// Name of the source package, same as the package name if there is no Source field
+    pub source: String,
+}
+
+impl InstalledPackage {
+    /// Check if the package is fully installed, and not only unpacked or with its configuration files left
+    pub fn is_installed(&self) -> bool {
+        self.status.rsplit(' ').next() == Some("installed")
+    }
+}
+
+/// In memory index of the dpkg status database
+#[derive(Debug, Default)]
+pub struct DpkgStatus {
+    /// Package records by name, several for packages installed for multiple architectures
+    packages: HashMap<String, Vec<InstalledPackage>>,
+}
+
+impl DpkgStatus {
+    /// Parse dpkg status database content
+    pub fn parse(content: &str) -> Result<DpkgStatus, Error> {
+        let mut packages: HashMap<String, Vec<InstalledPackage>> = HashMap::new();
+        for record in content.split("\n\n").filter(|r| !r.trim().is_empty()) {
+            let get_field = |name| {
+                get_control_field(record, name)
+                    .ok_or_else(|| Error::parse("dpkg status", &format!("missing {} field", name)))
+            };
+            let name = get_field("Package")?;
+            // Source field may have the source version if it differs, ie. 'mesa (22.3.6-1)'
+            let source = match get_control_field(record, "Source") {
+                Some(source) => source.split(' ').next().unwrap().to_string(),
+                None => name.clone(),
+            };
+            let package = InstalledPackage {
+                name,
+                version: match get_field("Version") {
+                    Ok(version) => version,
+                    // Packages in 'not-installed' state have no version
+                    Err(_) => continue,
+                },
+                arch: get_field("Architecture")?,
+                status: get_field("Status")?,
+                depends: join(
+                    ["Pre-Depends", "Depends"]
+                        .iter()
+                        .filter_map(|f| get_control_field(record, f)),
+                    ", ",
+                ),
+                source,
+            };
+            packages
+                .entry(package.name.clone())
+                .or_default()
+                .push(package);
+        }
+        Ok(DpkgStatus { packages })
+    }
+
+    /// Read and parse dpkg status database file
+    pub fn load(filepath: &Path) -> Result<DpkgStatus, Error> {
+        debug!("Reading {:?}", filepath);
+        let content = fs::read_to_string(filepath)?;
+        let status = DpkgStatus::parse(&content)?;
+        debug!("{} package(s) in dpkg status", status.packages.len());
+        Ok(status)
+    }
+
+    /// Get installed package by name, preferring the native architecture for packages installed for
+    /// multiple architectures
+    pub fn get_installed(
+        &self,
+        package_name: &str,
+        native_arch: &str,
+    ) -> Option<&InstalledPackage> {
+        let installed: Vec<&InstalledPackage> = self
+            .packages
+            .get(package_name)?
+            .iter()
+            .filter(|p| p.is_installed())
+            .collect();
+        installed
+            .iter()
+            .find(|p| (p.arch == native_arch) || (p.arch == "all"))
+            .or_else(|| installed.first())
+            .cloned()
+    }
+
+    /// Get names of fully installed packages built from a source package, sorted, given the source package name or
+    /// the name of one of its installed binary packages
+    pub fn get_installed_from_source(&self, name: &str) -> Vec<String> {
+        let source_name = self
+            .iter_installed()
+            .find(|p| p.name == name)
+            .map_or(name, |p| p.source.as_str());
+        let mut names: Vec<String> = self
+            .iter_installed()
+            .filter(|p| p.source == source_name)
+            .map(|p| p.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Iterate over fully installed packages, for all architectures
+    pub fn iter_installed(&self) -> impl Iterator<Item = &InstalledPackage> {
+        self.packages
+            .values()
+            .flatten()
+            .filter(|p| p.is_installed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dpkg_status() {
+        let status = DpkgStatus::parse(include_str!("../test_data/dpkg/status")).unwrap();
+
+        assert_eq!(
+            status.get_installed("libssl3", "amd64"),
+            Some(&InstalledPackage {
+                name: "libssl3".to_string(),
+                version: "3.0.19-1~deb12u2".to_string(),
+                arch: "amd64".to_string(),
+                status: "install ok installed".to_string(),
+                depends: "libc6 (>= 2.34)".to_string(),
+                source: "openssl".to_string(),
+            })
+        );
+        assert_eq!(status.iter_installed().count(), 5);
+
+        // Source packages
+        assert_eq!(
+            status.get_installed_from_source("openssl"),
+            vec!["libssl3", "openssl"]
+        );
+        assert_eq!(
+            status.get_installed_from_source("libssl3"),
+            vec!["libssl3", "openssl"]
+        );
+        assert_eq!(status.get_installed_from_source("glibc"), vec!["libc6"]);
+        assert!(status.get_installed_from_source("cowsay").is_empty());
+        assert_eq!(
+            DpkgStatus::parse("Package: libgl1-mesa-dri\nStatus: install ok installed\nArchitecture: amd64\nSource: mesa (22.3.6-1+deb12u1)\nVersion: 22.3.6-1+deb12u1+b1\n")
+                .unwrap()
+                .get_installed_from_source("mesa"),
+            vec!["libgl1-mesa-dri"]
+        );
+        assert_eq!(
+            status.get_installed("vim-common", "amd64").unwrap().version,
+            "2:9.0.1378-2+deb12u2"
+        );
+
+        // Multiarch
+        assert_eq!(
+            status.get_installed("libc6", "amd64").unwrap().arch,
+            "amd64"
+        );
+        assert_eq!(status.get_installed("libc6", "i386").unwrap().arch, "i386");
+        assert_eq!(
+            status.get_installed("libc6", "arm64").unwrap().arch,
+            "amd64"
+        );
+
+        // Removed, with configuration files left
+        assert_eq!(status.get_installed("cowsay", "amd64"), None);
+
+        assert_eq!(status.get_installed("p1", "amd64"), None);
+
+        assert!(DpkgStatus::parse("Package: p1\nVersion: 1.0\n").is_err());
+        assert!(DpkgStatus::parse("").unwrap().packages.is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+use itertools::join;
+
+/// Process exit code when there is nothing to install
+pub const EXIT_NOTHING_TO_DO: i32 = 2;
+/// Process exit code when some dependencies can not be satisfied
+pub const EXIT_UNSATISFIABLE: i32 = 3;
+/// Process exit code when incompatible versions of a package are required
+pub const EXIT_CONFLICT: i32 = 4;
+/// Process exit code when a network request failed
+pub const EXIT_NETWORK: i32 = 5;
+/// Process exit code when an external command failed
+pub const EXIT_COMMAND: i32 = 6;
+/// Process exit code when a command output or remote page could not be parsed
+pub const EXIT_PARSE: i32 = 7;
+/// Process exit code when the system does not match the state a plan was made for
+pub const EXIT_PRECONDITION: i32 = 8;
+/// Process exit code for all other errors
+pub const EXIT_OTHER: i32 = 1;
+
+/// Error generated when a command returns non zero code
+#[derive(Debug)]
+pub struct CommandError {
+    pub status: ExitStatus,
+    pub cmd: Vec<String>,
+    /// Messages explaining the failure, usually from standard error
+    pub output: String,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command {} ", join(&self.cmd, " "))?;
+        match self.status.code() {
+            Some(code) => write!(f, "returned {}", code)?,
+            None => write!(f, "killed by signal {}", self.status.signal().unwrap())?,
+        }
+        let output = self.output.trim_end();
+        if !output.is_empty() {
+            write!(f, ":\n{}", output)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+/// Error generated when a remote page does not have the expected structure
+#[derive(Debug)]
+pub struct HtmlParseError {
+    pub url: String,
+    pub element: String,
+    pub reason: String,
+}
+
+impl HtmlParseError {
+    pub fn new(url: &str, element: &str, reason: &str) -> HtmlParseError {
+        HtmlParseError {
+            url: url.to_string(),
+            element: element.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for HtmlParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unexpected HTML in {}: {} ({})",
+            self.url, self.reason, self.element
+        )
+    }
+}
+
+impl error::Error for HtmlParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+/// Errors of APT operations
+#[derive(Debug)]
+pub enum Error {
+    /// External command returned non zero code
+    Command(CommandError),
+
+    /// Unexpected command output or file content
+    Parse {
+        /// What was being parsed
+        context: String,
+        message: String,
+    },
+
+    /// Unexpected remote page structure
+    Html(HtmlParseError),
+
+    /// HTTP request failed
+    Network(reqwest::Error),
+
+    /// File or command I/O failed
+    Io(io::Error),
+
+    /// Incompatible versions of a package are required
+    Conflict {
+        package_name: String,
+        versions: Vec<String>,
+    },
+
+    /// Some dependencies can not be satisfied
+    Unsatisfiable {
+        /// Description of each unsatisfied dependency edge
+        dependencies: Vec<String>,
+        offline: bool,
+    },
+
+    /// Installing packages would break installed packages depending on them
+    BrokenReverseDependencies {
+        /// Description of each broken dependency
+        dependencies: Vec<String>,
+    },
+
+    /// APT would remove installed packages that were not requested to be removed
+    Removal {
+        /// Description of each package to remove
+        packages: Vec<String>,
+    },
+
+    /// The system does not match the state a plan was made for
+    Precondition {
+        /// Description of each mismatch
+        mismatches: Vec<String>,
+    },
+
+    /// There is nothing to install
+    NothingToDo,
+
+    /// Any other error
+    Other(String),
+}
+
+impl Error {
+    /// Build a parse error
+    pub fn parse(context: &str, message: &str) -> Error {
+        Error::Parse {
+            context: context.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Get process exit code for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NothingToDo => EXIT_NOTHING_TO_DO,
+            Error::Unsatisfiable { .. } => EXIT_UNSATISFIABLE,
+            Error::Conflict { .. }
+            | Error::BrokenReverseDependencies { .. }
+            | Error::Removal { .. } => EXIT_CONFLICT,
+            Error::Precondition { .. } => EXIT_PRECONDITION,
+            Error::Network(_) => EXIT_NETWORK,
+            Error::Command(_) => EXIT_COMMAND,
+            Error::Parse { .. } | Error::Html(_) => EXIT_PARSE,
+            Error::Io(_) | Error::Other(_) => EXIT_OTHER,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Command(e) => write!(f, "{}", e),
+            Error::Parse { context, message } => {
+                write!(f, "Unable to parse {}: {}", context, message)
+            }
+            Error::Html(e) => write!(f, "{}", e),
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Conflict {
+                package_name,
+                versions,
+            } => write!(
+                f,
+                "Conflicting versions required for {}: {}",
+                package_name,
+                join(versions, ", ")
+            ),
+            Error::Unsatisfiable {
+                dependencies,
+                offline,
+            } => {
+                write!(
+                    f,
+                    "Unable to resolve {} dependencie(s){}:",
+                    dependencies.len(),
+                    if *offline { " offline" } else { "" }
+                )?;
+                for dependency in dependencies {
+                    write!(f, "\n  {}", dependency)?;
+                }
+                Ok(())
+            }
+            Error::BrokenReverseDependencies { dependencies } => {
+                write!(
+                    f,
+                    "Installing packages would break {} installed dependencie(s):",
+                    dependencies.len()
+                )?;
+                for dependency in dependencies {
+                    write!(f, "\n  {}", dependency)?;
+                }
+                Ok(())
+            }
+            Error::Removal { packages } => {
+                write!(
+                    f,
+                    "APT would remove {} installed package(s), use --allow-remove to continue:",
+                    packages.len()
+                )?;
+                for package in packages {
+                    write!(f, "\n  {}", package)?;
+                }
+                Ok(())
+            }
+            Error::Precondition { mismatches } => {
+                write!(
+                    f,
+                    "System does not match plan for {} package(s):",
+                    mismatches.len()
+                )?;
+                for mismatch in mismatches {
+                    write!(f, "\n  {}", mismatch)?;
+                }
+                Ok(())
+            }
+            Error::NothingToDo => write!(f, "Nothing to do"),
+            Error::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Command(e) => Some(e),
+            Error::Html(e) => Some(e),
+            Error::Network(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(e: CommandError) -> Self {
+        Error::Command(e)
+    }
+}
+
+impl From<HtmlParseError> for Error {
+    fn from(e: HtmlParseError) -> Self {
+        Error::Html(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let errors = [
+            Error::NothingToDo,
+            Error::Unsatisfiable {
+                dependencies: vec!["requested p1=1.0".to_string()],
+                offline: false,
+            },
+            Error::Conflict {
+                package_name: "p1".to_string(),
+                versions: vec!["1.0".to_string(), "2.0".to_string()],
+            },
+            Error::Precondition {
+                mismatches: vec!["p1 is not installed".to_string()],
+            },
+            Error::Command(CommandError {
+                status: ExitStatus::from_raw(1 << 8),
+                cmd: vec!["apt-cache".to_string(), "show".to_string()],
+                output: String::new(),
+            }),
+            Error::parse("apt-cache show output", "missing Version field"),
+            Error::Other("error".to_string()),
+        ];
+        let exit_codes: Vec<i32> = errors.iter().map(Error::exit_code).collect();
+        assert_eq!(
+            exit_codes,
+            vec![
+                EXIT_NOTHING_TO_DO,
+                EXIT_UNSATISFIABLE,
+                EXIT_CONFLICT,
+                EXIT_PRECONDITION,
+                EXIT_COMMAND,
+                EXIT_PARSE,
+                EXIT_OTHER
+            ]
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "Unable to resolve 1 dependencie(s):\n  requested p1=1.0"
+        );
+        assert_eq!(errors[4].to_string(), "Command apt-cache show returned 1");
+    }
+}
@@ -0,0 +1,183 @@
+use std::thread;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+use reqwest::Proxy;
+
+use crate::error::Error;
+
+/// Network configuration values, read from APT configuration
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkConfig {
+    /// Proxy for HTTP URLs (Acquire::http::Proxy)
+    pub http_proxy: Option<String>,
+
+    /// Proxy for HTTPS URLs (Acquire::https::Proxy)
+    pub https_proxy: Option<String>,
+
+    /// Number of retries for failed requests (Acquire::Retries)
+    pub retries: u32,
+
+    /// Connection and read timeout (Acquire::http::Timeout)
+    pub timeout: Duration,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        // Same defaults as APT
+        NetworkConfig {
+            http_proxy: None,
+            https_proxy: None,
+            retries: 3,
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Delay before the first retry, doubled for each following one
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// HTTP client shared by all requests
+pub struct HttpClient {
+    client: Client,
+
+    retries: u32,
+}
+
+impl HttpClient {
+    /// Build client from network configuration
+    pub fn new(config: &NetworkConfig) -> Result<HttpClient, Error> {
+        let mut builder = Client::builder()
+            .user_agent(format!(
+                "apt-downgrade/{} (+https://github.com/desbma/apt-downgrade)",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .connect_timeout(config.timeout)
+            .timeout(config.timeout);
+        if let Some(http_proxy) = &config.http_proxy {
+            debug!("Using HTTP proxy {}", http_proxy);
+            builder = builder.proxy(Proxy::http(http_proxy)?);
+        }
+        if let Some(https_proxy) = &config.https_proxy {
+            debug!("Using HTTPS proxy {}", https_proxy);
+            builder = builder.proxy(Proxy::https(https_proxy)?);
+        }
+        Ok(HttpClient {
+            client: builder.build()?,
+            retries: config.retries,
+        })
+    }
+
+    /// Send a GET request, retrying with exponential backoff on connection errors and server errors
+    pub fn get(&self, url: &str, headers: HeaderMap) -> Result<Response, Error> {
+        let mut attempt = 0;
+        loop {
+            debug!("GET {}", url);
+            let result = self.client.get(url).headers(headers.clone()).send();
+            let retryable = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(e) => !e.is_builder() && !e.is_redirect(),
+            };
+            if !retryable || (attempt >= self.retries) {
+                return Ok(result?.error_for_status()?);
+            }
+            let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+            match &result {
+                Ok(response) => warn!(
+                    "GET {} failed with status {}, retrying in {}s",
+                    url,
+                    response.status(),
+                    delay.as_secs()
+                ),
+                Err(e) => warn!(
+                    "GET {} failed: {}, retrying in {}s",
+                    url,
+                    e,
+                    delay.as_secs()
+                ),
+            }
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// Send a GET request to each URL in turn until one succeeds, and return the URL with its response
+    pub fn get_first(
+        &self,
+        urls: &[String],
+        headers: HeaderMap,
+    ) -> Result<(String, Response), Error> {
+        let mut last_error = Error::Other("No URL to get".to_string());
+        for url in urls {
+            match self.get(url, headers.clone()) {
+                Ok(response) => return Ok((url.to_string(), response)),
+                Err(e) => {
+                    warn!("Failed to get {}: {}", url, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Start a local HTTP server answering a single request with a status and body, and return its base URL
+    fn serve_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_get_first() {
+        let http_client = HttpClient::new(&NetworkConfig {
+            retries: 0,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let missing_url = format!("{}p1.deb", serve_once("404 Not Found", ""));
+        let ok_url = format!("{}p1.deb", serve_once("200 OK", "content"));
+        let (url, response) = http_client
+            .get_first(
+                &[
+                    "http://127.0.0.1:1/p1.deb".to_string(),
+                    missing_url,
+                    ok_url.clone(),
+                ],
+                HeaderMap::new(),
+            )
+            .unwrap();
+        assert_eq!(url, ok_url);
+        assert_eq!(response.text().unwrap(), "content");
+
+        assert!(http_client
+            .get_first(&["http://127.0.0.1:1/p1.deb".to_string()], HeaderMap::new())
+            .is_err());
+        assert!(http_client.get_first(&[], HeaderMap::new()).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use itertools::join;
+
+use crate::apt;
+use crate::dpkg::DpkgStatus;
+use crate::error::Error;
+
+/// Change that would break a dependency
+#[derive(Clone, Debug)]
+pub enum BreakingChange {
+    /// Package to install that does not satisfy the dependency
+    Install(apt::Package),
+
+    /// Names of installed packages satisfying the dependency, that would be removed
+    Remove(Vec<String>),
+}
+
+impl fmt::Display for BreakingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakingChange::Install(package) => {
+                write!(f, "{} {} would be installed", package.name, package.version)
+            }
+            BreakingChange::Remove(package_names) => {
+                write!(f, "{} would be removed", join(package_names, ", "))
+            }
+        }
+    }
+}
+
+/// Installed package with a dependency that would no longer be satisfied after installing or removing packages
+#[derive(Clone, Debug)]
+pub struct BrokenReverseDependency {
+    /// Installed package
+    pub package: apt::Package,
+
+    /// Dependency alternatives, none of which would be satisfied
+    pub dependency: Vec<apt::PackageDependency>,
+
+    /// Change that breaks the dependency
+    pub change: BreakingChange,
+}
+
+impl fmt::Display for BrokenReverseDependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} depends on {}, but {}",
+            self.package.name,
+            self.package.version,
+            join(&self.dependency, " | "),
+            self.change
+        )
+    }
+}
+
+/// Find installed packages depending on packages to install or remove, whose dependencies are satisfied now, but
+/// would not be after installing packages and removing others
+pub fn find_broken_reverse_dependencies(
+    to_install: &[apt::Package],
+    to_remove: &[String],
+    dpkg_status: &DpkgStatus,
+    apt_env: &apt::AptEnv,
+) -> Result<Vec<BrokenReverseDependency>, Error> {
+    let targets: HashMap<&str, &apt::Package> =
+        to_install.iter().map(|p| (p.name.as_str(), p)).collect();
+    let is_removed = |name: &str| to_remove.iter().any(|r| r == name);
+    let is_satisfied_by_installed = |dependency: &apt::PackageDependency| {
+        apt::get_installed_version(&dependency.package_name, dpkg_status, apt_env)
+            .map_or(false, |p| dependency.is_satisfied_by(&p))
+    };
+
+    let mut broken: Vec<BrokenReverseDependency> = Vec::new();
+    for installed_package in dpkg_status.iter_installed() {
+        if targets.contains_key(installed_package.name.as_str())
+            || is_removed(&installed_package.name)
+            // Avoid parsing fields that can not reference any package to install or remove
+            || !targets
+                .keys()
+                .copied()
+                .chain(to_remove.iter().map(String::as_str))
+                .any(|n| installed_package.depends.contains(n))
+        {
+            continue;
+        }
+        let package =
+            match apt::get_installed_version(&installed_package.name, dpkg_status, apt_env) {
+                Some(package) => package,
+                None => continue,
+            };
+
+        // One unusual installed package should not prevent checking the others
+        let dependencies =
+            match apt::parse_dependency_field(&installed_package.depends, "dpkg status") {
+                Ok(dependencies) => dependencies,
+                Err(e) => {
+                    warn!(
+                        "Unable to check dependencies of {}: {}",
+                        installed_package.name, e
+                    );
+                    continue;
+                }
+            };
+        for alternatives in dependencies {
+            let change = match alternatives
+                .iter()
+                .find_map(|d| targets.get(d.package_name.as_str()))
+            {
+                Some(target) => BreakingChange::Install((*target).clone()),
+                None => {
+                    let mut removed: Vec<String> = alternatives
+                        .iter()
+                        .filter(|d| is_removed(&d.package_name) && is_satisfied_by_installed(d))
+                        .map(|d| d.package_name.clone())
+                        .collect();
+                    if removed.is_empty() {
+                        continue;
+                    }
+                    removed.dedup();
+                    BreakingChange::Remove(removed)
+                }
+            };
+
+            // Dependencies that are not satisfied now are likely on virtual packages, ignore them
+            if !alternatives.iter().any(is_satisfied_by_installed) {
+                continue;
+            }
+            let is_satisfied_after =
+                alternatives
+                    .iter()
+                    .any(|d| match targets.get(d.package_name.as_str()) {
+                        Some(target) => d.is_satisfied_by(target),
+                        None => !is_removed(&d.package_name) && is_satisfied_by_installed(d),
+                    });
+            if is_satisfied_after {
+                continue;
+            }
+
+            // Packages installed for several architectures have the same dependencies
+            let broken_dependency = BrokenReverseDependency {
+                package: package.clone(),
+                dependency: alternatives,
+                change,
+            };
+            if !broken
+                .iter()
+                .any(|b| b.to_string() == broken_dependency.to_string())
+            {
+                broken.push(broken_dependency);
+            }
+        }
+    }
+
+    broken.sort_by(|a, b| a.package.name.cmp(&b.package.name));
+    Ok(broken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str) -> apt::Package {
+        apt::Package {
+            name: name.to_string(),
+            version: apt::PackageVersion {
+                string: version.to_string(),
+            },
+            arch: Some("amd64".to_string()),
+            filepath: None,
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_find_broken_reverse_dependencies() {
+        let apt_env = apt::AptEnv::for_tests();
+        let dpkg_status = DpkgStatus::parse(include_str!("../test_data/dpkg/status")).unwrap();
+
+        let broken = find_broken_reverse_dependencies(
+            &[package("libc6", "2.31-13+deb11u5")],
+            &[],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(
+            broken.iter().map(|b| b.to_string()).collect::<Vec<String>>(),
+            vec![
+                "libssl3 3.0.19-1~deb12u2 depends on libc6>=2.34, but libc6 2.31-13+deb11u5 would be installed",
+                "openssl 3.0.19-1~deb12u2 depends on libc6>=2.34, but libc6 2.31-13+deb11u5 would be installed"
+            ]
+        );
+        let broken = find_broken_reverse_dependencies(
+            &[package("libssl3", "1.1.1w-0+deb11u1")],
+            &[],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].package.name, "openssl");
+
+        // Still satisfied
+        assert!(find_broken_reverse_dependencies(
+            &[
+                package("libc6", "2.36-9+deb12u4"),
+                package("libssl3", "3.0.11-1~deb12u2")
+            ],
+            &[],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap()
+        .is_empty());
+
+        // Reverse dependency is also installed or removed
+        assert!(find_broken_reverse_dependencies(
+            &[
+                package("libc6", "2.31-13+deb11u5"),
+                package("libssl3", "1.1.1w-0+deb11u1"),
+                package("openssl", "1.1.1w-0+deb11u1")
+            ],
+            &[],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap()
+        .is_empty());
+        assert!(find_broken_reverse_dependencies(
+            &[package("libc6", "2.31-13+deb11u5")],
+            &["libssl3".to_string(), "openssl".to_string()],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap()
+        .is_empty());
+
+        // Alternatives
+        let dpkg_status = DpkgStatus::parse(
+            "Package: p1\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.0\nDepends: p2 (>= 2.0) | p3, p4\n\n\
+             Package: p2\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.0\n\n\
+             Package: p3\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.0\n",
+        )
+        .unwrap();
+        assert!(find_broken_reverse_dependencies(
+            &[package("p2", "1.0")],
+            &[],
+            &dpkg_status,
+            &apt_env
+        )
+        .unwrap()
+        .is_empty());
+        let broken = find_broken_reverse_dependencies(
+            &[package("p2", "1.0")],
+            &["p3".to_string()],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].dependency.len(), 2);
+        match &broken[0].change {
+            BreakingChange::Install(target) => assert_eq!(target.name, "p2"),
+            change => panic!("Unexpected change: {}", change),
+        }
+
+        // Malformed dependencies of an installed package
+        let malformed_dpkg_status = DpkgStatus::parse(
+            "Package: p0\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.0\nDepends: p2 (~= 2.0)\n\n\
+             Package: p1\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.0\nDepends: p2 (>= 2.0)\n\n\
+             Package: p2\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.0\n",
+        )
+        .unwrap();
+        let broken = find_broken_reverse_dependencies(
+            &[package("p2", "1.0")],
+            &[],
+            &malformed_dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].package.name, "p1");
+
+        // Removed dependencies
+        let broken = find_broken_reverse_dependencies(
+            &[],
+            &["p2".to_string(), "p3".to_string()],
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(
+            broken
+                .iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<String>>(),
+            vec!["p1 1.0 depends on p2>=2.0 | p3, but p2, p3 would be removed"]
+        );
+        assert!(
+            find_broken_reverse_dependencies(&[], &["p3".to_string()], &dpkg_status, &apt_env)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(find_broken_reverse_dependencies(
+            &[],
+            &["p1".to_string(), "p2".to_string(), "p3".to_string()],
+            &dpkg_status,
+            &apt_env
+        )
+        .unwrap()
+        .is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::apt;
+use crate::dpkg::DpkgStatus;
+use crate::error::Error;
+use crate::http::HttpClient;
+use crate::metadata::MetadataCache;
+use crate::plan::PlannedPackage;
+use crate::runner::CommandRunner;
+
+/// Version of the lock file schema, to increment on incompatible changes
+const SCHEMA_VERSION: u32 = 1;
+
+/// Lock file entry for a single package
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LockedPackage {
+    pub name: String,
+
+    /// Version installed when the plan was made, absent if not installed
+    pub installed_version: Option<String>,
+
+    pub version: String,
+
+    pub arch: Option<String>,
+
+    pub url: Option<String>,
+
+    /// Path of the package file on the machine the plan was made on
+    pub filepath: Option<String>,
+
+    pub sha256: String,
+}
+
+/// Lock file entry for an installed package to remove
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RemovedPackage {
+    pub name: String,
+
+    /// Version installed when the plan was made
+    pub installed_version: String,
+}
+
+/// Resolved plan that can be applied later, or on other machines, without resolving dependencies again
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct LockFile {
+    pub schema_version: u32,
+
+    /// Installed packages to remove, because their dependencies would be broken
+    #[serde(default)]
+    pub remove: Vec<RemovedPackage>,
+
+    pub packages: Vec<LockedPackage>,
+}
+
+impl LockFile {
+    /// Build lock file from resolved packages and packages to remove, files of resolved packages must be available
+    /// locally or in APT indexes
+    pub fn new(
+        planned_packages: &[PlannedPackage],
+        to_remove: &[String],
+        runner: &dyn CommandRunner,
+        dpkg_status: &DpkgStatus,
+        apt_env: &apt::AptEnv,
+    ) -> Result<LockFile, Error> {
+        let remove = to_remove
+            .iter()
+            .map(|name| {
+                let installed_package = apt::get_installed_version(name, dpkg_status, apt_env)
+                    .ok_or_else(|| {
+                        Error::Other(format!("Package to remove {} is not installed", name))
+                    })?;
+                Ok(RemovedPackage {
+                    name: name.clone(),
+                    installed_version: installed_package.version.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut packages = Vec::new();
+        for planned_package in planned_packages {
+            let package = &planned_package.package;
+            let sha256 = match (&package.filepath, &package.arch) {
+                (Some(filepath), _) if Path::new(filepath).is_file() => {
+                    Some(apt::get_file_sha256(Path::new(filepath))?)
+                }
+                (_, Some(arch)) => apt::get_repo_package_sha256(
+                    &package.name,
+                    &package.version.string,
+                    arch,
+                    runner,
+                    apt_env,
+                )?,
+                _ => None,
+            }
+            .ok_or_else(|| {
+                Error::Other(format!(
+                    "Unable to get SHA256 of {} {}",
+                    package.name, package.version
+                ))
+            })?;
+            packages.push(LockedPackage {
+                name: package.name.clone(),
+                installed_version: planned_package
+                    .installed_package
+                    .as_ref()
+                    .map(|p| p.version.to_string()),
+                version: package.version.to_string(),
+                arch: package.arch.clone(),
+                url: package.url.clone(),
+                filepath: package.filepath.clone(),
+                sha256,
+            });
+        }
+        Ok(LockFile {
+            schema_version: SCHEMA_VERSION,
+            remove,
+            packages,
+        })
+    }
+
+    /// Parse lock file content
+    pub fn parse(content: &str) -> Result<LockFile, Error> {
+        let lock_file: LockFile =
+            toml::from_str(content).map_err(|e| Error::parse("lock file", &e.to_string()))?;
+        if lock_file.schema_version != SCHEMA_VERSION {
+            return Err(Error::parse(
+                "lock file",
+                &format!(
+                    "unsupported schema version {}, expected {}",
+                    lock_file.schema_version, SCHEMA_VERSION
+                ),
+            ));
+        }
+        Ok(lock_file)
+    }
+
+    /// Read lock file
+    pub fn load(filepath: &Path) -> Result<LockFile, Error> {
+        LockFile::parse(&fs::read_to_string(filepath)?)
+    }
+
+    /// Serialize lock file
+    pub fn to_toml(&self) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::Other(e.to_string()))
+    }
+
+    /// Get names of installed packages to remove
+    pub fn remove_names(&self) -> Vec<String> {
+        self.remove.iter().map(|p| p.name.clone()).collect()
+    }
+
+    /// Get descriptions of installed packages, to install or remove, that differ from the time the plan was made
+    pub fn check_preconditions(
+        &self,
+        dpkg_status: &DpkgStatus,
+        apt_env: &apt::AptEnv,
+    ) -> Vec<String> {
+        let expected_versions = self
+            .packages
+            .iter()
+            .map(|p| (&p.name, p.installed_version.clone()))
+            .chain(
+                self.remove
+                    .iter()
+                    .map(|p| (&p.name, Some(p.installed_version.clone()))),
+            );
+        let mut mismatches = Vec::new();
+        for (name, expected_version) in expected_versions {
+            let installed_version = apt::get_installed_version(name, dpkg_status, apt_env)
+                .map(|p| p.version.to_string());
+            if installed_version == expected_version {
+                continue;
+            }
+            let describe = |version: &Option<String>| match version {
+                Some(version) => format!("{} installed", version),
+                None => "not installed".to_string(),
+            };
+            mismatches.push(format!(
+                "{}: {}, plan expects {}",
+                name,
+                describe(&installed_version),
+                describe(&expected_version)
+            ));
+        }
+        mismatches
+    }
+
+    /// Get package files, downloading them if needed, and check their SHA256
+    pub fn fetch(
+        &self,
+        offline: bool,
+        http_client: &HttpClient,
+        metadata_cache: &mut MetadataCache,
+        apt_env: &apt::AptEnv,
+    ) -> Result<Vec<apt::Package>, Error> {
+        let mut packages = Vec::new();
+        for locked_package in &self.packages {
+            let mut package = apt::Package {
+                name: locked_package.name.clone(),
+                version: apt::PackageVersion {
+                    string: locked_package.version.clone(),
+                },
+                arch: locked_package.arch.clone(),
+                filepath: None,
+                url: locked_package.url.clone(),
+            };
+
+            // Prefer the file used to make the plan if it is also here
+            if let Some(filepath) = &locked_package.filepath {
+                let filepath = Path::new(filepath);
+                if filepath.is_file() && (apt::get_file_sha256(filepath)? == locked_package.sha256)
+                {
+                    package.filepath = Some(filepath.to_string_lossy().to_string());
+                }
+            }
+
+            if package.filepath.is_none() {
+                if package.url.is_none() {
+                    return Err(Error::Other(format!(
+                        "No file or URL to get {} {} from",
+                        package.name, package.version
+                    )));
+                }
+                let url = package.url.as_ref().unwrap();
+                if offline && !apt::is_available_offline(&package) {
+                    return Err(Error::Other(format!("{:?} is not available offline", url)));
+                }
+                apt::download_package(&mut package, http_client, metadata_cache, apt_env)?;
+                let filepath = Path::new(package.filepath.as_ref().unwrap());
+                let sha256 = apt::get_file_sha256(filepath)?;
+                if sha256 != locked_package.sha256 {
+                    // Remove file so that it is downloaded again next time
+                    fs::remove_file(filepath)?;
+                    return Err(Error::Other(format!(
+                        "SHA256 mismatch for {:?}: got {}, expected {}",
+                        filepath, sha256, locked_package.sha256
+                    )));
+                }
+            }
+
+            packages.push(package);
+        }
+        Ok(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::http::NetworkConfig;
+    use crate::metadata::DEFAULT_TTL;
+    use crate::runner::ReplayRunner;
+
+    #[test]
+    fn test_lock_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let deb_filepath = tmp_dir.path().join("libssl3_3.0.11-1~deb12u2_amd64.deb");
+        fs::write(&deb_filepath, "").unwrap();
+        let apt_env = apt::AptEnv::for_tests();
+        let dpkg_status = DpkgStatus::parse(include_str!("../test_data/dpkg/status")).unwrap();
+        let planned_packages = vec![PlannedPackage {
+            package: apt::Package {
+                name: "libssl3".to_string(),
+                version: apt::PackageVersion {
+                    string: "3.0.11-1~deb12u2".to_string(),
+                },
+                arch: Some("amd64".to_string()),
+                filepath: Some(deb_filepath.to_str().unwrap().to_string()),
+                url: Some("http://deb.debian.org/debian/pool/main/o/openssl/libssl3_3.0.11-1~deb12u2_amd64.deb".to_string()),
+            },
+            installed_package: Some(apt::Package {
+                name: "libssl3".to_string(),
+                version: apt::PackageVersion {
+                    string: "3.0.19-1~deb12u2".to_string(),
+                },
+                arch: Some("amd64".to_string()),
+                filepath: None,
+                url: None,
+            }),
+            parent: None,
+            dependency: "libssl3=3.0.11-1~deb12u2".parse().unwrap(),
+            required_by: Vec::new(),
+        }];
+
+        let lock_file = LockFile::new(
+            &planned_packages,
+            &["openssl".to_string()],
+            &ReplayRunner::default(),
+            &dpkg_status,
+            &apt_env,
+        )
+        .unwrap();
+        assert_eq!(
+            lock_file.packages[0].sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            lock_file.packages[0].installed_version,
+            Some("3.0.19-1~deb12u2".to_string())
+        );
+        let toml = lock_file.to_toml().unwrap();
+        assert!(toml.contains("[[packages]]"));
+        assert!(toml.contains(
+            "[[remove]]\nname = \"openssl\"\ninstalled_version = \"3.0.19-1~deb12u2\"\n"
+        ));
+        assert_eq!(LockFile::parse(&toml).unwrap(), lock_file);
+        assert_eq!(lock_file.remove_names(), vec!["openssl"]);
+        assert!(LockFile::new(
+            &planned_packages,
+            &["libfoo1".to_string()],
+            &ReplayRunner::default(),
+            &dpkg_status,
+            &apt_env,
+        )
+        .is_err());
+
+        // Preconditions
+        assert!(lock_file
+            .check_preconditions(&dpkg_status, &apt_env)
+            .is_empty());
+        let mut drifted_lock_file = LockFile::parse(&toml).unwrap();
+        drifted_lock_file.packages[0].installed_version = Some("3.0.17-1~deb12u2".to_string());
+        drifted_lock_file.packages.push(LockedPackage {
+            name: "vim-common".to_string(),
+            installed_version: None,
+            version: "9.0.1378-2".to_string(),
+            arch: Some("all".to_string()),
+            url: None,
+            filepath: None,
+            sha256: "00".to_string(),
+        });
+        drifted_lock_file.remove[0].installed_version = "3.0.17-1~deb12u2".to_string();
+        drifted_lock_file.remove.push(RemovedPackage {
+            name: "libfoo1".to_string(),
+            installed_version: "1.0".to_string(),
+        });
+        assert_eq!(
+            drifted_lock_file.check_preconditions(&dpkg_status, &apt_env),
+            vec![
+                "libssl3: 3.0.19-1~deb12u2 installed, plan expects 3.0.17-1~deb12u2 installed",
+                "vim-common: 9.0.1378-2+deb12u2 installed, plan expects not installed",
+                "openssl: 3.0.19-1~deb12u2 installed, plan expects 3.0.17-1~deb12u2 installed",
+                "libfoo1: not installed, plan expects 1.0 installed"
+            ]
+        );
+
+        // Fetch from local file
+        let http_client = HttpClient::new(&NetworkConfig::default()).unwrap();
+        let mut metadata_cache = MetadataCache::new(DEFAULT_TTL, false);
+        let packages = lock_file
+            .fetch(true, &http_client, &mut metadata_cache, &apt_env)
+            .unwrap();
+        assert_eq!(
+            packages[0].filepath,
+            Some(deb_filepath.to_str().unwrap().to_string())
+        );
+
+        // Local file does not match, and no URL
+        fs::write(&deb_filepath, "corrupted").unwrap();
+        let mut corrupted_lock_file = LockFile::parse(&toml).unwrap();
+        corrupted_lock_file.packages[0].url = None;
+        assert!(corrupted_lock_file
+            .fetch(true, &http_client, &mut metadata_cache, &apt_env)
+            .is_err());
+
+        assert!(LockFile::parse("schema_version = 2\npackages = []\n").is_err());
+        assert!(LockFile::parse("packages = []\n").is_err());
+        assert!(LockFile::parse("schema_version = 1\npackages = []\n")
+            .unwrap()
+            .remove
+            .is_empty());
+    }
+}
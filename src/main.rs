@@ -1,24 +1,278 @@
 use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
+use dialoguer::{Confirm, Select};
 use itertools::join;
 use stderrlog::ColorChoice;
 
+use crate::error::Error;
+
 #[macro_use]
 extern crate log;
 
 mod apt;
+mod cache;
+mod changelog;
+mod config;
+mod dpkg;
+mod error;
+mod http;
+mod impact;
+mod lock;
+mod metadata;
+mod plan;
+mod repo;
+mod runner;
+mod simulation;
+
+/// Action to run
+#[derive(Clone)]
+enum Action {
+    /// Downgrade packages
+    Downgrade {
+        package_specs: Vec<apt::PackageDependency>,
+
+        dry_run: bool,
+
+        explain: bool,
+
+        output_format: plan::OutputFormat,
+
+        /// Flat repository directory to write packages to, instead of installing them
+        repo_dir: Option<PathBuf>,
+
+        /// Continue even if APT would remove packages
+        allow_remove: bool,
+
+        /// Install without asking for confirmation
+        yes: bool,
+    },
+
+    /// List available versions of a package
+    List { package_name: String },
+
+    /// Show changelog entries between installed and target versions of a package
+    Changelog {
+        package_spec: apt::PackageDependency,
+    },
+
+    /// Resolve packages and write lock file
+    Plan {
+        package_specs: Vec<apt::PackageDependency>,
+
+        /// Lock file path, standard output if None
+        out: Option<PathBuf>,
+    },
+
+    /// Install packages from lock file
+    Apply {
+        plan_filepath: PathBuf,
+
+        dry_run: bool,
+
+        /// Flat repository directory to write packages to, instead of installing them
+        repo_dir: Option<PathBuf>,
+
+        /// Continue even if APT would remove packages
+        allow_remove: bool,
+
+        /// Install without asking for confirmation
+        yes: bool,
+    },
+
+    /// Manage downloaded packages
+    Cache(CacheAction),
+}
+
+/// Action on the downloaded package cache
+#[derive(Clone)]
+enum CacheAction {
+    /// List cached packages with their size
+    List,
+
+    /// Remove files of interrupted downloads
+    Clean,
+
+    /// Remove oldest packages
+    Prune {
+        max_age: Option<Duration>,
+
+        max_size: Option<u64>,
+    },
+
+    /// Check cached packages against known checksums
+    Verify,
+
+    /// Copy packages from APT archive cache
+    Import,
+
+    /// Copy packages to APT archive cache
+    Export,
+}
+
+/// What to do with installed packages whose dependencies would be broken by the downgrade
+#[derive(Clone, Copy, PartialEq)]
+enum BrokenReverseDependencyAction {
+    /// Ask interactively, abort if not running in an interactive terminal
+    Ask,
+
+    /// Downgrade them too
+    Downgrade,
+
+    /// Remove them
+    Remove,
+
+    /// Abort with the list of broken dependencies
+    Abort,
+}
 
 /// Parsed command line arguments
 #[derive(Clone)]
 struct CLArgs {
-    package_name: String,
+    action: Action,
+
+    offline: bool,
+
+    refresh: bool,
+
+    deb_dirs: Vec<PathBuf>,
+
+    mirrors: Vec<String>,
+
+    admin_dir: Option<PathBuf>,
+
+    root: Option<PathBuf>,
+
+    broken_rdeps: BrokenReverseDependencyAction,
+
+    with_recommends: bool,
+
+    with_suggests: bool,
+
+    /// Package specs are source packages, or binary packages standing for their source package
+    source: bool,
+}
+
+/// Sources to get package candidates from
+struct CandidateSources {
+    /// Only use sources that do not require network access
+    offline: bool,
+
+    /// Client shared by all HTTP requests
+    http_client: http::HttpClient,
 
-    package_version: apt::PackageVersion,
+    /// Runner for apt-cache and dpkg-deb invocations
+    runner: Box<dyn runner::CommandRunner>,
 
-    dry_run: bool,
+    /// Packages currently installed
+    dpkg_status: dpkg::DpkgStatus,
+
+    /// Persistent cache of remote and .deb file metadata
+    metadata_cache: metadata::MetadataCache,
+
+    /// Additional directories containing .deb files
+    deb_dirs: Vec<PathBuf>,
+
+    /// Packages found in deb_dirs by name, scanned on first use
+    deb_dir_packages: Option<HashMap<String, Vec<apt::Package>>>,
+}
+
+impl CandidateSources {
+    fn new(
+        offline: bool,
+        http_client: http::HttpClient,
+        runner: Box<dyn runner::CommandRunner>,
+        dpkg_status: dpkg::DpkgStatus,
+        metadata_cache: metadata::MetadataCache,
+        deb_dirs: Vec<PathBuf>,
+    ) -> CandidateSources {
+        CandidateSources {
+            offline,
+            http_client,
+            runner,
+            dpkg_status,
+            metadata_cache,
+            deb_dirs,
+            deb_dir_packages: None,
+        }
+    }
+
+    /// Get packages found in additional directories for a package name
+    fn get_deb_dir_package_versions(
+        &mut self,
+        package_name: &str,
+        apt_env: &apt::AptEnv,
+    ) -> Vec<apt::Package> {
+        if self.deb_dir_packages.is_none() && !self.deb_dirs.is_empty() {
+            info!("Scanning {} package directorie(s)...", self.deb_dirs.len());
+            self.deb_dir_packages = Some(apt::scan_deb_dirs(
+                &self.deb_dirs,
+                self.runner.as_ref(),
+                &mut self.metadata_cache,
+                apt_env,
+            ));
+        }
+        self.deb_dir_packages
+            .as_ref()
+            .and_then(|p| p.get(package_name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Save metadata gathered so far for the next runs
+    fn save(&self) {
+        if let Err(e) = self.metadata_cache.save() {
+            warn!("Unable to save metadata cache: {}", e);
+        }
+    }
+}
+
+/// Dependency that could not be resolved, with the package that depends on it if any
+type UnresolvedDependency = (Option<apt::Package>, apt::PackageDependency);
+
+/// Build flat repository directory argument
+fn repo_dir_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("REPO_DIR")
+        .long("repo-dir")
+        .takes_value(true)
+        .help("Instead of installing packages, write them to this directory with index files, to use it as a flat APT repository, it must be served over HTTP to be used on other machines")
+}
+
+/// Build argument allowing APT to remove packages
+fn allow_remove_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("ALLOW_REMOVE")
+        .long("allow-remove")
+        .help("Continue even if APT would remove installed packages that were not requested to be removed")
+}
+
+/// Build argument to skip install confirmation
+fn yes_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("YES")
+        .short("y")
+        .long("yes")
+        .help("Install without asking for confirmation")
+}
+
+/// Build package spec positional argument
+fn package_spec_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("PACKAGE_SPEC")
+        .required(true)
+        .multiple(true)
+        .validator(|s| {
+            s.parse::<apt::PackageDependency>()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+}
+
+/// Parse package spec arguments, already validated by Clap, each one being a separate package
+fn parse_package_specs<'a>(args: impl Iterator<Item = &'a str>) -> Vec<apt::PackageDependency> {
+    args.map(|s| s.parse().unwrap()).collect()
 }
 
 /// Parse and validate command line arguments
@@ -28,15 +282,10 @@ fn parse_cl_args() -> CLArgs {
         .version(env!("CARGO_PKG_VERSION"))
         .about("Downgrade debian packages and their dependencies")
         .author("desbma")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
-            Arg::with_name("PACKAGE_NAME")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("PACKAGE_VERSION")
-                .required(true)
-                .takes_value(true),
+            package_spec_arg()
+                .help("Package and version constraint to downgrade to, ie. 'name=version', 'name<<version' or 'name=version.*', if no version is given, it is chosen interactively"),
         )
         .arg(
             Arg::with_name("DRY_RUN")
@@ -44,23 +293,273 @@ fn parse_cl_args() -> CLArgs {
                 .long("dry-run")
                 .help("Only display install command, but do not install anything"),
         )
+        .arg(
+            Arg::with_name("EXPLAIN")
+                .long("explain")
+                .alias("tree")
+                .help("Display dependency tree explaining why each package is installed"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT_FORMAT")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json", "yaml"])
+                .default_value("text")
+                .help("Format of the resolved plan written to standard output, 'json' and 'yaml' imply --dry-run"),
+        )
+        .arg(repo_dir_arg())
+        .arg(allow_remove_arg())
+        .arg(yes_arg())
+        .arg(
+            Arg::with_name("OFFLINE")
+                .long("offline")
+                .global(true)
+                .help("Do not use network, only get packages from local caches and APT indexes"),
+        )
+        .arg(
+            Arg::with_name("REFRESH")
+                .long("refresh")
+                .global(true)
+                .help("Ignore cached package metadata, and download it again"),
+        )
+        .arg(
+            Arg::with_name("DEB_DIR")
+                .long("deb-dir")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Additional directory to search recursively for .deb files, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("MIRROR")
+                .long("mirror")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Base URL of archive mirror to download packages from, ie. 'http://localhost:3142/debian/', can be repeated to fall back between mirrors (default: mirror from APT sources)"),
+        )
+        .arg(
+            Arg::with_name("ROOT")
+                .long("root")
+                .takes_value(true)
+                .global(true)
+                .help("Operate on the system installed in this directory (chroot, container rootfs...) instead of the running one"),
+        )
+        .arg(
+            Arg::with_name("ADMIN_DIR")
+                .long("admindir")
+                .takes_value(true)
+                .global(true)
+                .help("dpkg database directory to read installed packages from (default: from APT configuration, usually '/var/lib/dpkg')"),
+        )
+        .arg(
+            Arg::with_name("SOURCE")
+                .long("source")
+                .global(true)
+                .help("Downgrade all installed binary packages built from the same source package, package specs being source package names or one of their binary packages, with the source version"),
+        )
+        .arg(
+            Arg::with_name("WITH_RECOMMENDS")
+                .long("with-recommends")
+                .global(true)
+                .help("Also downgrade installed recommended packages to compatible versions, and let APT install missing ones"),
+        )
+        .arg(
+            Arg::with_name("WITH_SUGGESTS")
+                .long("with-suggests")
+                .global(true)
+                .help("Also downgrade installed suggested packages to compatible versions, and let APT install missing ones"),
+        )
+        .arg(
+            Arg::with_name("BROKEN_RDEPS")
+                .long("broken-rdeps")
+                .takes_value(true)
+                .possible_values(&["ask", "downgrade", "remove", "abort"])
+                .default_value("ask")
+                .global(true)
+                .help("What to do with installed packages whose dependencies the downgraded packages would no longer satisfy"),
+        )
         .arg(
             Arg::with_name("verbosity")
                 .short("v")
                 .multiple(true)
+                .global(true)
                 .help("Increase message verbosity"),
         )
         .arg(
             Arg::with_name("quiet")
                 .short("q")
+                .global(true)
                 .help("Silence all output"),
         )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List available versions of a package")
+                .arg(
+                    Arg::with_name("PACKAGE_NAME")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("changelog")
+                .alias("diff")
+                .about("Show changelog entries between installed and target versions of a package, highlighting CVE identifiers")
+                .arg(
+                    package_spec_arg()
+                        .multiple(false)
+                        .help("Package and version constraint to compare with installed version, if no version is given, it is chosen interactively"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Resolve packages to downgrade, and write a lock file to apply later, possibly on other machines")
+                .arg(
+                    package_spec_arg()
+                        .help("Package and version constraint to downgrade to, if no version is given, it is chosen interactively"),
+                )
+                .arg(
+                    Arg::with_name("OUT")
+                        .long("out")
+                        .takes_value(true)
+                        .help("Lock file to write (default: standard output)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Install packages from a lock file, without resolving dependencies again")
+                .arg(
+                    Arg::with_name("PLAN_FILE")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("DRY_RUN")
+                        .short("d")
+                        .long("dry-run")
+                        .help("Only check the system and fetch packages, but do not install anything"),
+                )
+                .arg(repo_dir_arg())
+                .arg(allow_remove_arg())
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage packages downloaded by apt-downgrade")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list").about("List downloaded packages with their size"),
+                )
+                .subcommand(
+                    SubCommand::with_name("clean")
+                        .about("Remove leftover files of interrupted downloads"),
+                )
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Remove oldest downloaded packages")
+                        .arg(
+                            Arg::with_name("MAX_AGE")
+                                .long("max-age")
+                                .takes_value(true)
+                                .validator(|s| cache::parse_age(&s).map(|_| ()))
+                                .required_unless("MAX_SIZE")
+                                .help("Remove packages downloaded more than this number of days ago"),
+                        )
+                        .arg(
+                            Arg::with_name("MAX_SIZE")
+                                .long("max-size")
+                                .takes_value(true)
+                                .validator(|s| cache::parse_size(&s).map(|_| ()))
+                                .help("Remove oldest packages until total size is below this size, ie. '500M' or '2G'"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify").about(
+                        "Check downloaded packages against SHA256 from APT indexes, or from download time",
+                    ),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Copy packages from APT archive cache to apt-downgrade cache"),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Copy packages from apt-downgrade cache to APT archive cache"),
+                ),
+        )
         .get_matches();
 
     // Post Clap parsing
-    let package_name = matches.value_of("PACKAGE_NAME").unwrap().to_string();
-    let package_version = matches.value_of("PACKAGE_VERSION").unwrap();
-    let dry_run = matches.is_present("DRY_RUN");
+    let action = match matches.subcommand() {
+        ("list", Some(list_matches)) => Action::List {
+            package_name: list_matches.value_of("PACKAGE_NAME").unwrap().to_string(),
+        },
+        ("changelog", Some(changelog_matches)) => Action::Changelog {
+            package_spec: changelog_matches
+                .value_of("PACKAGE_SPEC")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        },
+        ("plan", Some(plan_matches)) => Action::Plan {
+            package_specs: parse_package_specs(plan_matches.values_of("PACKAGE_SPEC").unwrap()),
+            out: plan_matches.value_of("OUT").map(PathBuf::from),
+        },
+        ("apply", Some(apply_matches)) => Action::Apply {
+            plan_filepath: PathBuf::from(apply_matches.value_of("PLAN_FILE").unwrap()),
+            dry_run: apply_matches.is_present("DRY_RUN"),
+            repo_dir: apply_matches.value_of("REPO_DIR").map(PathBuf::from),
+            allow_remove: apply_matches.is_present("ALLOW_REMOVE"),
+            yes: apply_matches.is_present("YES"),
+        },
+        ("cache", Some(cache_matches)) => Action::Cache(match cache_matches.subcommand() {
+            ("list", _) => CacheAction::List,
+            ("clean", _) => CacheAction::Clean,
+            ("prune", Some(prune_matches)) => CacheAction::Prune {
+                max_age: prune_matches
+                    .value_of("MAX_AGE")
+                    .map(|d| cache::parse_age(d).unwrap()),
+                max_size: prune_matches
+                    .value_of("MAX_SIZE")
+                    .map(|s| cache::parse_size(s).unwrap()),
+            },
+            ("verify", _) => CacheAction::Verify,
+            ("import", _) => CacheAction::Import,
+            ("export", _) => CacheAction::Export,
+            _ => unreachable!(),
+        }),
+        _ => Action::Downgrade {
+            package_specs: parse_package_specs(matches.values_of("PACKAGE_SPEC").unwrap()),
+            dry_run: matches.is_present("DRY_RUN"),
+            explain: matches.is_present("EXPLAIN"),
+            output_format: matches.value_of("OUTPUT_FORMAT").unwrap().parse().unwrap(),
+            repo_dir: matches.value_of("REPO_DIR").map(PathBuf::from),
+            allow_remove: matches.is_present("ALLOW_REMOVE"),
+            yes: matches.is_present("YES"),
+        },
+    };
+    let offline = matches.is_present("OFFLINE");
+    let refresh = matches.is_present("REFRESH");
+    let deb_dirs = match matches.values_of("DEB_DIR") {
+        Some(deb_dirs) => deb_dirs.map(PathBuf::from).collect(),
+        None => Vec::new(),
+    };
+    let mirrors = match matches.values_of("MIRROR") {
+        Some(mirrors) => mirrors.map(apt::normalize_mirror_url).collect(),
+        None => Vec::new(),
+    };
+    let admin_dir = matches.value_of("ADMIN_DIR").map(PathBuf::from);
+    let root = matches.value_of("ROOT").map(PathBuf::from);
+    let broken_rdeps = match matches.value_of("BROKEN_RDEPS").unwrap() {
+        "ask" => BrokenReverseDependencyAction::Ask,
+        "downgrade" => BrokenReverseDependencyAction::Downgrade,
+        "remove" => BrokenReverseDependencyAction::Remove,
+        "abort" => BrokenReverseDependencyAction::Abort,
+        _ => unreachable!(),
+    };
     let verbose = 2 + matches.occurrences_of("verbosity") as usize;
     let quiet = matches.is_present("quiet");
 
@@ -74,100 +573,1575 @@ fn parse_cl_args() -> CLArgs {
         .unwrap();
 
     CLArgs {
-        package_name,
-        package_version: apt::PackageVersion {
-            string: package_version.to_string(),
-        },
-        dry_run,
+        action,
+        offline,
+        refresh,
+        deb_dirs,
+        mirrors,
+        admin_dir,
+        root,
+        broken_rdeps,
+        with_recommends: matches.is_present("WITH_RECOMMENDS"),
+        with_suggests: matches.is_present("WITH_SUGGESTS"),
+        source: matches.is_present("SOURCE"),
     }
 }
 
-fn main() {
-    // Parse args
-    let cl_args = parse_cl_args();
-
-    // Get global apt env
-    let apt_env = apt::read_apt_env().expect("Unable to read APT environment");
-
-    // Initial queue states
-    let mut to_resolve: VecDeque<apt::PackageDependency> = VecDeque::new();
-    to_resolve.push_back(apt::PackageDependency {
-        package_name: cl_args.package_name,
-        version_constraints: vec![apt::PackageVersionConstaint {
-            version: cl_args.package_version,
-            version_relation: apt::PackageVersionRelation::Equal,
-        }],
-    });
-    let mut to_install: Vec<apt::Package> = Vec::new();
-    let mut html_cache: HashMap<String, String> = HashMap::new();
-
-    info!("Analyzing dependencies...");
+/// Add candidates to a list, ignoring versions already present, but keeping their URL if the present one has none
+fn extend_candidates(
+    package_candidates: &mut Vec<apt::Package>,
+    new_candidates: Vec<apt::Package>,
+) {
+    let known_versions: HashSet<apt::PackageVersion> =
+        HashSet::from_iter(package_candidates.iter().map(|c| c.version.clone()));
+    for new_candidate in new_candidates {
+        if !known_versions.contains(&new_candidate.version) {
+            package_candidates.push(new_candidate);
+            continue;
+        }
+        if let Some(known_candidate) = package_candidates.iter_mut().find(|c| {
+            (c.version == new_candidate.version)
+                && (c.arch == new_candidate.arch)
+                && c.url.is_none()
+        }) {
+            known_candidate.url = new_candidate.url;
+        }
+    }
+}
 
-    // Resolve packages to install
-    let mut progress = 0;
-    while let Some(dependency) = to_resolve.pop_front() {
-        // Get candidates
-        let installed_package = apt::get_installed_version(&dependency.package_name, &apt_env);
-        let mut package_candidates =
-            apt::get_cache_package_versions(&dependency.package_name, &apt_env).unwrap();
-        match apt::get_remote_package_versions(&dependency.package_name, &mut html_cache, &apt_env)
-        {
+/// Get all candidate versions of a package from local caches, APT repositories and remote
+fn get_package_candidates(
+    package_name: &str,
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<Vec<apt::Package>, Error> {
+    let mut package_candidates =
+        apt::get_cache_package_versions(package_name, &apt_env.cache_dir, apt_env)?;
+    let download_cache_dir = apt::get_download_cache_dir()?;
+    extend_candidates(
+        &mut package_candidates,
+        apt::get_cache_package_versions(
+            package_name,
+            &download_cache_dir.to_string_lossy(),
+            apt_env,
+        )?,
+    );
+    extend_candidates(
+        &mut package_candidates,
+        sources.get_deb_dir_package_versions(package_name, apt_env),
+    );
+    match apt::get_repo_package_versions(package_name, sources.runner.as_ref(), apt_env) {
+        Ok(new_candidates) => {
+            extend_candidates(&mut package_candidates, new_candidates);
+        }
+        Err(e) => {
+            debug!(
+                "Failed to get repository versions for {}: {}",
+                package_name, e
+            );
+        }
+    };
+    if sources.offline {
+        package_candidates.retain(apt::is_available_offline);
+    } else {
+        match apt::get_remote_package_versions(
+            package_name,
+            &sources.http_client,
+            &mut sources.metadata_cache,
+            apt_env,
+        ) {
             Ok(new_candidates) => {
-                let local_versions: HashSet<apt::PackageVersion> =
-                    HashSet::from_iter(package_candidates.iter().map(|c| c.version.clone()));
-                package_candidates.extend(
-                    new_candidates
-                        .iter()
-                        .filter(|c| !local_versions.contains(&c.version))
-                        .cloned(),
-                );
+                extend_candidates(&mut package_candidates, new_candidates);
             }
             Err(e) => {
                 error!(
                     "Failed to get remote dependencies for {}: {}",
-                    &dependency.package_name, e
+                    package_name, e
                 );
             }
         };
+    }
+    Ok(package_candidates)
+}
+
+/// Resolve requested packages and their dependencies, and return the list of packages to install,
+/// and dependencies that could not be resolved
+fn resolve_packages(
+    package_specs: &[apt::PackageDependency],
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<(Vec<plan::PlannedPackage>, Vec<UnresolvedDependency>), Error> {
+    // Constraints requested by user apply whatever package depends on them
+    let mut requested_constraints: HashMap<String, Vec<apt::PackageVersionConstaint>> =
+        HashMap::new();
+    for package_spec in package_specs {
+        requested_constraints
+            .entry(package_spec.package_name.clone())
+            .or_default()
+            .extend(package_spec.version_constraints.iter().cloned());
+    }
+
+    // Initial queue states, with dependencies flagged as weak if they come from Recommends or Suggests
+    let mut to_resolve: VecDeque<(apt::PackageDependency, Option<apt::Package>, bool)> =
+        VecDeque::new();
+    let mut requested_names: HashSet<&str> = HashSet::new();
+    for package_spec in package_specs {
+        if requested_names.insert(&package_spec.package_name) {
+            to_resolve.push_back((
+                apt::PackageDependency {
+                    package_name: package_spec.package_name.clone(),
+                    version_constraints: requested_constraints[&package_spec.package_name].clone(),
+                },
+                None,
+                false,
+            ));
+        }
+    }
+    let mut to_install: Vec<plan::PlannedPackage> = Vec::new();
+    let mut resolved: HashMap<String, apt::Package> = HashMap::new();
+    let mut required_by: HashMap<String, Vec<(Option<apt::Package>, apt::PackageDependency)>> =
+        HashMap::new();
+    let mut unresolved: Vec<UnresolvedDependency> = Vec::new();
+
+    // Resolve packages to install
+    let mut progress = 0;
+    while let Some((parent_dependency, parent, weak)) = to_resolve.pop_front() {
+        required_by
+            .entry(parent_dependency.package_name.clone())
+            .or_default()
+            .push((parent.clone(), parent_dependency.clone()));
+        let mut dependency = parent_dependency.clone();
+        if parent.is_some() {
+            if let Some(constraints) = requested_constraints.get(&dependency.package_name) {
+                dependency
+                    .version_constraints
+                    .extend(constraints.iter().cloned());
+            }
+        }
+
+        // Already resolved?
+        if let Some(resolved_package) = resolved.get(&dependency.package_name) {
+            if dependency.is_satisfied_by(resolved_package) {
+                continue;
+            }
+            if weak {
+                warn!(
+                    "Ignoring weak dependency: {}, {} {} is already selected",
+                    plan::describe_edge(&parent, &dependency),
+                    resolved_package.name,
+                    resolved_package.version
+                );
+                continue;
+            }
+            return Err(Error::Conflict {
+                package_name: dependency.package_name.clone(),
+                versions: vec![resolved_package.version.to_string(), dependency.to_string()],
+            });
+        }
+
+        // Get candidates
+        let installed_package =
+            apt::get_installed_version(&dependency.package_name, &sources.dpkg_status, apt_env);
+        let mut package_candidates =
+            get_package_candidates(&dependency.package_name, sources, apt_env)?;
 
-        // Resolve
+        // Resolve, favoring the installed version unless the user explicitly requested the package
         package_candidates.sort_unstable_by_key(|d| Reverse(d.version.clone()));
+        let favored_package = if requested_constraints.contains_key(&dependency.package_name) {
+            None
+        } else {
+            installed_package.clone()
+        };
         let mut resolved_package =
-            apt::resolve_dependency(&dependency, package_candidates, &installed_package)
-                .unwrap_or_else(|| panic!("Unable to resolve dependency {}", dependency));
+            match apt::resolve_dependency(&dependency, package_candidates, &favored_package) {
+                Some(resolved_package) => resolved_package,
+                None if weak => {
+                    warn!(
+                        "Ignoring weak dependency: {}, no matching version found",
+                        plan::describe_edge(&parent, &dependency)
+                    );
+                    continue;
+                }
+                None => {
+                    unresolved.push((parent, dependency));
+                    continue;
+                }
+            };
+        resolved.insert(resolved_package.name.clone(), resolved_package.clone());
 
         progress += 1;
         info!("Analyzing {} dependencie(s)...", progress);
 
-        // Already in install queue?
-        if to_install.contains(&resolved_package) {
-            continue;
-        }
-
         // Already installed?
-        if let Some(installed_package) = installed_package {
-            if installed_package == resolved_package {
+        if let Some(installed_package) = &installed_package {
+            if installed_package.version == resolved_package.version {
                 continue;
             }
         }
 
         // Get package dependencies
-        let deps = apt::get_dependencies(&mut resolved_package).unwrap();
-        to_resolve.extend(deps);
+        let deps = apt::get_dependencies(
+            &mut resolved_package,
+            sources.runner.as_ref(),
+            &sources.http_client,
+            &mut sources.metadata_cache,
+            apt_env,
+        )?;
+        to_resolve.extend(
+            deps.depends
+                .into_iter()
+                .map(|d| (d, Some(resolved_package.clone()), false)),
+        );
+
+        // Weak dependencies only matter for packages already installed, APT installs missing ones itself
+        let mut weak_deps = Vec::new();
+        if apt_env.install_recommends {
+            weak_deps.extend(deps.recommends);
+        }
+        if apt_env.install_suggests {
+            weak_deps.extend(deps.suggests);
+        }
+        for alternatives in weak_deps {
+            if let Some(dependency) = alternatives.into_iter().find(|d| {
+                apt::get_installed_version(&d.package_name, &sources.dpkg_status, apt_env).is_some()
+            }) {
+                to_resolve.push_back((dependency, Some(resolved_package.clone()), true));
+            }
+        }
 
         // Add to install queue
-        to_install.push(resolved_package.clone());
+        to_install.push(plan::PlannedPackage {
+            package: resolved_package,
+            installed_package,
+            parent,
+            dependency: parent_dependency,
+            required_by: Vec::new(),
+        });
+    }
+
+    for planned_package in &mut to_install {
+        if let Some(edges) = required_by.remove(&planned_package.package.name) {
+            planned_package.required_by = edges;
+        }
+    }
+
+    Ok((to_install, unresolved))
+}
+
+/// Get currently installed package if any, and all available versions of a package, sorted by version
+fn get_package_versions(
+    package_name: &str,
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<(Option<apt::Package>, Vec<apt::Package>), Error> {
+    let installed_package = apt::get_installed_version(package_name, &sources.dpkg_status, apt_env);
+    let mut packages = get_package_candidates(package_name, sources, apt_env)?;
+    if let Some(installed_package) = &installed_package {
+        if !packages
+            .iter()
+            .any(|p| p.version == installed_package.version)
+        {
+            packages.push(installed_package.clone());
+        }
+    }
+    packages.sort_by_key(|p| p.version.clone());
+    Ok((installed_package, packages))
+}
+
+/// Print all available versions of a package
+fn list_package_versions(
+    package_name: &str,
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<(), Error> {
+    let (installed_package, packages) = get_package_versions(package_name, sources, apt_env)?;
+
+    let rows: Vec<Vec<String>> = packages
+        .iter()
+        .map(|p| {
+            let installed = match &installed_package {
+                Some(installed_package) => installed_package.version == p.version,
+                None => false,
+            };
+            let downloaded = apt::is_available_offline(p);
+            let source = match (&p.filepath, &p.url) {
+                (Some(filepath), _) if !Path::new(filepath).is_file() => "installed".to_string(),
+                (Some(filepath), _) if filepath.starts_with(&apt_env.cache_dir) => {
+                    format!("local cache ({})", filepath)
+                }
+                (Some(filepath), _) => filepath.to_string(),
+                (None, Some(url)) => url.to_string(),
+                (None, None) => "unknown".to_string(),
+            };
+            vec![
+                p.version.to_string(),
+                p.arch.clone().unwrap_or_else(|| "?".to_string()),
+                (if installed { "yes" } else { "no" }).to_string(),
+                (if downloaded { "yes" } else { "no" }).to_string(),
+                source,
+            ]
+        })
+        .collect();
+
+    print_table(
+        &["VERSION", "ARCH", "INSTALLED", "DOWNLOADED", "SOURCE"],
+        &rows,
+    );
+
+    Ok(())
+}
+
+/// Print changelog entries between installed and target versions of a package
+fn show_changelog(
+    package_spec: &apt::PackageDependency,
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<(), Error> {
+    let package_spec =
+        pick_package_versions(std::slice::from_ref(package_spec), sources, apt_env)?.remove(0);
+    let package_name = &package_spec.package_name;
+    let installed_package = apt::get_installed_version(package_name, &sources.dpkg_status, apt_env)
+        .ok_or_else(|| Error::Other(format!("Package {} is not installed", package_name)))?;
+
+    // Get target package
+    let mut candidates = get_package_candidates(package_name, sources, apt_env)?;
+    candidates.sort_by_key(|p| Reverse(p.version.clone()));
+    let mut target_package =
+        apt::resolve_dependency(&package_spec, candidates, &None).ok_or_else(|| {
+            Error::Unsatisfiable {
+                dependencies: vec![package_spec.to_string()],
+                offline: sources.offline,
+            }
+        })?;
+    if target_package.version == installed_package.version {
+        return Err(Error::NothingToDo);
+    }
+    if target_package.filepath.is_none() {
+        apt::download_package(
+            &mut target_package,
+            &sources.http_client,
+            &mut sources.metadata_cache,
+            apt_env,
+        )?;
+    }
+
+    // Read changelogs
+    let runner = sources.runner.as_ref();
+    let target_changelog = changelog::read_deb_changelog(
+        Path::new(target_package.filepath.as_ref().unwrap()),
+        package_name,
+        runner,
+    )?;
+    let mut installed_changelog =
+        changelog::read_installed_changelog(package_name, apt_env.root.as_deref())?;
+    if installed_changelog.is_none() {
+        // Documentation may be excluded by dpkg, fall back to the package file in APT archive cache
+        if let Some(filepath) = installed_package
+            .filepath
+            .as_ref()
+            .filter(|f| Path::new(f).is_file())
+        {
+            installed_changelog =
+                changelog::read_deb_changelog(Path::new(filepath), package_name, runner)?;
+        }
+    }
+    for (changelog, package) in &[
+        (&installed_changelog, &installed_package),
+        (&target_changelog, &target_package),
+    ] {
+        if changelog.is_none() {
+            warn!(
+                "No changelog found for {} {}",
+                package.name, package.version
+            );
+        }
     }
+    let entries = changelog::get_entries_between(
+        &changelog::parse(installed_changelog.as_deref().unwrap_or("")),
+        &changelog::parse(target_changelog.as_deref().unwrap_or("")),
+        &installed_package.version,
+        &target_package.version,
+    );
 
-    // Install
-    if to_install.is_empty() {
-        info!("Nothing to do");
+    // Print
+    info!(
+        "Changes {} {} from {} to {}:",
+        if target_package.version < installed_package.version {
+            "lost by downgrading"
+        } else {
+            "gained by upgrading"
+        },
+        package_name,
+        installed_package.version,
+        target_package.version
+    );
+    let (highlight_start, highlight_end) = if atty::is(atty::Stream::Stdout) {
+        ("\x1b[1;31m", "\x1b[0m")
     } else {
-        let install_cmdline = apt::build_install_cmdline(to_install);
-        if cl_args.dry_run {
-            info!("Run:\n{}", join(install_cmdline, " "));
+        ("", "")
+    };
+    let mut cve_ids: Vec<String> = Vec::new();
+    let mut cve_entry_count = 0;
+    for entry in &entries {
+        let entry_cve_ids = entry.cve_ids();
+        if !entry_cve_ids.is_empty() {
+            cve_entry_count += 1;
+        }
+        for cve_id in entry_cve_ids {
+            if !cve_ids.contains(&cve_id) {
+                cve_ids.push(cve_id);
+            }
+        }
+        println!(
+            "{}\n",
+            changelog::highlight_cve_ids(&entry.text, highlight_start, highlight_end)
+        );
+    }
+    if entries.is_empty() {
+        warn!("No changelog entries found between these versions");
+    } else if !cve_ids.is_empty() {
+        warn!(
+            "{} of {} entries mention CVE identifiers: {}",
+            cve_entry_count,
+            entries.len(),
+            join(&cve_ids, ", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Format rows as table lines with aligned columns
+fn format_table(header: &[&str], rows: &[Vec<String>]) -> Vec<String> {
+    let header: Vec<String> = header.iter().map(|s| s.to_string()).collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .chain(std::iter::once(&header))
+                .map(|r| r[i].len())
+                .max()
+                .unwrap()
+        })
+        .collect();
+    std::iter::once(&header)
+        .chain(rows.iter())
+        .map(|row| {
+            join(
+                row.iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{:width$}", cell, width = width)),
+                "  ",
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect()
+}
+
+/// Print rows as a table with aligned columns
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    for line in format_table(header, rows) {
+        println!("{}", line);
+    }
+}
+
+/// Run an action on the downloaded package cache
+fn manage_cache(
+    action: &CacheAction,
+    runner: &dyn runner::CommandRunner,
+    metadata_cache: &mut metadata::MetadataCache,
+    apt_env: &apt::AptEnv,
+) -> Result<(), Error> {
+    let cache_dir = apt::get_download_cache_dir()?;
+    let now = SystemTime::now();
+
+    match action {
+        CacheAction::List => {
+            let packages = cache::list_packages(&cache_dir)?;
+            let rows: Vec<Vec<String>> = packages
+                .iter()
+                .map(|p| {
+                    vec![
+                        p.filename.clone(),
+                        cache::format_size(p.size),
+                        format!("{}", p.age(now).as_secs() / (24 * 60 * 60)),
+                    ]
+                })
+                .collect();
+            print_table(&["FILENAME", "SIZE", "AGE (DAYS)"], &rows);
+            println!(
+                "{} package(s), {} total in {:?}",
+                packages.len(),
+                cache::format_size(packages.iter().map(|p| p.size).sum()),
+                cache_dir
+            );
+        }
+        CacheAction::Clean => {
+            let removed = cache::remove_tmp_files(&cache_dir)?;
+            info!("Removed {} leftover file(s)", removed.len());
+        }
+        CacheAction::Prune { max_age, max_size } => {
+            let packages = cache::list_packages(&cache_dir)?;
+            let to_remove = cache::select_prune(&packages, *max_age, *max_size, now);
+            for package in &to_remove {
+                info!("Removing {}", package.filename);
+                fs::remove_file(&package.filepath)?;
+            }
+            info!(
+                "Removed {} package(s), freed {}",
+                to_remove.len(),
+                cache::format_size(to_remove.iter().map(|p| p.size).sum())
+            );
+        }
+        CacheAction::Verify => {
+            let mut mismatch_count = 0;
+            for package in cache::list_packages(&cache_dir)? {
+                let repo_sha256 = match package.parse_filename() {
+                    Some((name, version, arch)) => {
+                        apt::get_repo_package_sha256(&name, &version, &arch, runner, apt_env)?
+                    }
+                    None => None,
+                };
+                let known_sha256 = repo_sha256.or_else(|| {
+                    metadata_cache
+                        .get_download_sha256(&package.filename)
+                        .cloned()
+                });
+                let status = match known_sha256 {
+                    Some(known_sha256) => {
+                        if apt::get_file_sha256(&package.filepath)? == known_sha256 {
+                            "OK"
+                        } else {
+                            mismatch_count += 1;
+                            "MISMATCH"
+                        }
+                    }
+                    None => "UNKNOWN",
+                };
+                println!("{}  {}", status, package.filename);
+            }
+            if mismatch_count > 0 {
+                return Err(Error::Other(format!(
+                    "{} package(s) do not match their known checksum",
+                    mismatch_count
+                )));
+            }
+        }
+        CacheAction::Import => {
+            let copied = cache::copy_packages(Path::new(&apt_env.cache_dir), &cache_dir)?;
+            info!("Copied {} package(s) to {:?}", copied.len(), cache_dir);
+        }
+        CacheAction::Export => {
+            let copied = cache::copy_packages(&cache_dir, Path::new(&apt_env.cache_dir))?;
+            info!(
+                "Copied {} package(s) to {:?}",
+                copied.len(),
+                apt_env.cache_dir
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Choose versions not given on command line, and resolve packages to install and installed packages to remove,
+/// failing if some dependencies can not be resolved
+fn resolve_package_specs(
+    package_specs: &[apt::PackageDependency],
+    source: bool,
+    broken_rdeps: BrokenReverseDependencyAction,
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<(Vec<plan::PlannedPackage>, Vec<String>), Error> {
+    let package_specs = if source {
+        expand_source_package_specs(package_specs, sources, apt_env)?
+    } else {
+        package_specs.to_vec()
+    };
+    let mut package_specs = pick_package_versions(&package_specs, sources, apt_env)?;
+    let mut to_remove: Vec<String> = Vec::new();
+
+    loop {
+        info!("Analyzing dependencies...");
+
+        let resolution = resolve_packages(&package_specs, sources, apt_env);
+        sources.save();
+        let (to_install, unresolved) = resolution?;
+        if !unresolved.is_empty() {
+            return Err(Error::Unsatisfiable {
+                dependencies: unresolved
+                    .iter()
+                    .map(|(parent, dependency)| plan::describe_edge(parent, dependency))
+                    .collect(),
+                offline: sources.offline,
+            });
+        }
+
+        // Check installed packages that depend on the ones to install
+        let packages: Vec<apt::Package> = to_install.iter().map(|p| p.package.clone()).collect();
+        let broken = impact::find_broken_reverse_dependencies(
+            &packages,
+            &to_remove,
+            &sources.dpkg_status,
+            apt_env,
+        )?;
+        if broken.is_empty() {
+            return Ok((to_install, to_remove));
+        }
+        let broken_error = Error::BrokenReverseDependencies {
+            dependencies: broken.iter().map(|b| b.to_string()).collect(),
+        };
+        let mut broken_packages: Vec<&apt::Package> = broken.iter().map(|b| &b.package).collect();
+        broken_packages.dedup_by(|a, b| a.name == b.name);
+
+        match choose_broken_rdeps_action(&broken, broken_rdeps)? {
+            BrokenReverseDependencyAction::Downgrade => {
+                for package in broken_packages {
+                    if package_specs.iter().any(|s| s.package_name == package.name) {
+                        // Already tried to downgrade it
+                        return Err(broken_error);
+                    }
+                    info!("Also downgrading {} from {}", package.name, package.version);
+                    package_specs.push(apt::PackageDependency {
+                        package_name: package.name.clone(),
+                        version_constraints: vec![apt::PackageVersionConstaint {
+                            version: package.version.clone(),
+                            version_relation: apt::PackageVersionRelation::StrictlyInferior,
+                        }],
+                    });
+                }
+            }
+            BrokenReverseDependencyAction::Remove => {
+                to_remove.extend(broken_packages.iter().map(|p| p.name.clone()));
+            }
+            BrokenReverseDependencyAction::Abort | BrokenReverseDependencyAction::Ask => {
+                return Err(broken_error);
+            }
+        }
+    }
+}
+
+/// Expand specs of source packages, or binary packages standing for their source package, to specs of all
+/// installed binary packages built from them, with the same version constraints
+fn expand_source_package_specs(
+    package_specs: &[apt::PackageDependency],
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<Vec<apt::PackageDependency>, Error> {
+    let mut expanded_specs = Vec::new();
+    for package_spec in package_specs {
+        let package_names = sources
+            .dpkg_status
+            .get_installed_from_source(&package_spec.package_name);
+        if package_names.is_empty() {
+            return Err(Error::Other(format!(
+                "No installed package built from source package {}",
+                package_spec.package_name
+            )));
+        }
+        info!(
+            "Installed packages built from the same source as {}: {}",
+            package_spec.package_name,
+            join(&package_names, ", ")
+        );
+
+        // Choose version once, for the given package if it is a binary one
+        let picked_spec = pick_package_versions(
+            &[apt::PackageDependency {
+                package_name: if package_names.contains(&package_spec.package_name) {
+                    package_spec.package_name.clone()
+                } else {
+                    package_names[0].clone()
+                },
+                version_constraints: package_spec.version_constraints.clone(),
+            }],
+            sources,
+            apt_env,
+        )?
+        .remove(0);
+
+        // Binary versions differ from the source version for binary only rebuilds (binNMU), so select the source
+        // version first, and then the most recent build of it for each binary package
+        let source_constraints: Vec<apt::PackageVersionConstaint> = picked_spec
+            .version_constraints
+            .iter()
+            .map(|c| apt::PackageVersionConstaint {
+                version: c.version.source_version(),
+                version_relation: c.version_relation.clone(),
+            })
+            .collect();
+        let source_version = get_package_candidates(&picked_spec.package_name, sources, apt_env)?
+            .into_iter()
+            .map(|c| c.version.source_version())
+            .filter(|v| source_constraints.iter().all(|c| c.matches(v)))
+            .max()
+            .ok_or_else(|| Error::Unsatisfiable {
+                dependencies: vec![format!("no version found for {}", picked_spec)],
+                offline: sources.offline,
+            })?;
+        debug!(
+            "Source version for {}: {}",
+            package_spec.package_name, source_version
+        );
+        for package_name in package_names {
+            let version = get_package_candidates(&package_name, sources, apt_env)?
+                .into_iter()
+                .map(|c| c.version)
+                .filter(|v| v.source_version() == source_version)
+                .max()
+                .ok_or_else(|| Error::Unsatisfiable {
+                    dependencies: vec![format!(
+                        "no version of {} built from source version {}",
+                        package_name, source_version
+                    )],
+                    offline: sources.offline,
+                })?;
+            expanded_specs.push(apt::PackageDependency {
+                package_name,
+                version_constraints: vec![apt::PackageVersionConstaint {
+                    version,
+                    version_relation: apt::PackageVersionRelation::Equal,
+                }],
+            });
+        }
+    }
+    Ok(expanded_specs)
+}
+
+/// Choose what to do with broken reverse dependencies, interactively if needed
+fn choose_broken_rdeps_action(
+    broken: &[impact::BrokenReverseDependency],
+    action: BrokenReverseDependencyAction,
+) -> Result<BrokenReverseDependencyAction, Error> {
+    if (action != BrokenReverseDependencyAction::Ask)
+        || !atty::is(atty::Stream::Stdin)
+        || !atty::is(atty::Stream::Stderr)
+    {
+        return Ok(action);
+    }
+
+    let rows: Vec<Vec<String>> = broken
+        .iter()
+        .map(|b| {
+            vec![
+                b.package.name.clone(),
+                b.package.version.to_string(),
+                join(&b.dependency, " | "),
+                match &b.change {
+                    impact::BreakingChange::Install(target) => {
+                        format!("install {} {}", target.name, target.version)
+                    }
+                    impact::BreakingChange::Remove(package_names) => {
+                        format!("remove {}", join(package_names, ", "))
+                    }
+                },
+            ]
+        })
+        .collect();
+    // Standard output may be reserved for the plan
+    for line in format_table(&["PACKAGE", "INSTALLED", "DEPENDS ON", "CHANGE"], &rows) {
+        eprintln!("{}", line);
+    }
+
+    let actions = [
+        BrokenReverseDependencyAction::Downgrade,
+        BrokenReverseDependencyAction::Remove,
+        BrokenReverseDependencyAction::Abort,
+    ];
+    let selected = Select::new()
+        .with_prompt("Dependencies of installed packages would be broken")
+        .items(&["Downgrade them too", "Remove them", "Abort"])
+        .default(0)
+        .interact_opt()?;
+    Ok(selected.map_or(BrokenReverseDependencyAction::Abort, |i| actions[i]))
+}
+
+/// Interactively choose a version for unversioned package specs, with a preview of the resulting install plan
+fn pick_package_versions(
+    package_specs: &[apt::PackageDependency],
+    sources: &mut CandidateSources,
+    apt_env: &apt::AptEnv,
+) -> Result<Vec<apt::PackageDependency>, Error> {
+    let mut package_specs = package_specs.to_vec();
+
+    for i in 0..package_specs.len() {
+        if !package_specs[i].is_unversioned() {
+            continue;
+        }
+        let package_name = package_specs[i].package_name.clone();
+        if !atty::is(atty::Stream::Stdin) || !atty::is(atty::Stream::Stderr) {
+            return Err(Error::Other(format!(
+                "No version given for {}, and not running in an interactive terminal",
+                package_name
+            )));
+        }
+
+        let (installed_package, mut packages) =
+            get_package_versions(&package_name, sources, apt_env)?;
+        if packages.is_empty() {
+            return Err(Error::Unsatisfiable {
+                dependencies: vec![format!("no version found for {}", package_name)],
+                offline: sources.offline,
+            });
+        }
+        packages.reverse();
+        let items: Vec<String> = packages
+            .iter()
+            .map(|p| {
+                let mut item = format!(
+                    "{} ({})",
+                    p.version,
+                    p.arch.as_ref().map_or("?", String::as_str)
+                );
+                if let Some(installed_package) = &installed_package {
+                    if installed_package.version == p.version {
+                        item.push_str(" [installed]");
+                    }
+                }
+                item
+            })
+            .collect();
+
+        let mut selected = 0;
+        loop {
+            selected = match Select::new()
+                .with_prompt(format!("Version of {} to install", package_name))
+                .items(&items)
+                .default(selected)
+                .paged(true)
+                .interact_opt()?
+            {
+                Some(selected) => selected,
+                None => return Err(Error::Other("Aborted".to_string())),
+            };
+
+            // Preview
+            package_specs[i] = format!("{}={}", package_name, packages[selected].version)
+                .parse()
+                .unwrap();
+            let (to_install, unresolved) = match resolve_packages(&package_specs, sources, apt_env)
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    (Vec::new(), Vec::new())
+                }
+            };
+            for (parent, dependency) in &unresolved {
+                eprintln!(
+                    "Unable to resolve {}",
+                    plan::describe_edge(parent, dependency)
+                );
+            }
+            if to_install.is_empty() {
+                eprintln!("Nothing to install");
+            } else {
+                eprintln!("Packages to install:");
+                for planned_package in &to_install {
+                    eprintln!(
+                        "  {} {}",
+                        planned_package.package.name, planned_package.package.version
+                    );
+                }
+            }
+            if Confirm::new().with_prompt("Use this version?").interact()? {
+                break;
+            }
+        }
+    }
+
+    Ok(package_specs)
+}
+
+/// Write resolved plan in a machine readable format, as the only document of the output
+fn write_plan(
+    out: &mut dyn std::io::Write,
+    to_install: &[plan::PlannedPackage],
+    to_remove: &[String],
+    output_format: plan::OutputFormat,
+    apt_env: &apt::AptEnv,
+) -> Result<(), Error> {
+    let plan = plan::Plan::new(to_install, to_remove, apt_env)?;
+    plan.write(&mut *out, output_format)?;
+    if output_format == plan::OutputFormat::Json {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write packages to a flat repository directory, and print the sources.list line to use it, to standard error if
+/// standard output is reserved for the plan
+fn write_repo(
+    packages: &[apt::Package],
+    repo_dir: &Path,
+    stdout_reserved: bool,
+    runner: &dyn runner::CommandRunner,
+) -> Result<(), Error> {
+    repo::write_flat_repo(packages, repo_dir, runner)?;
+    let (local_line, served_line) = repo::get_sources_list_lines(repo_dir)?;
+    let print_line = |line: &str| {
+        if stdout_reserved {
+            eprintln!("{}", line);
         } else {
-            unimplemented!();
+            println!("{}", line);
+        }
+    };
+    info!(
+        "Wrote {} package(s) to {:?}, add this line to APT sources to use them on this machine:",
+        packages.len(),
+        repo_dir
+    );
+    print_line(&local_line);
+    info!("On other machines or in Docker builds, serve the directory over HTTP, and use this line with its URL:");
+    print_line(&served_line);
+    Ok(())
+}
+
+/// Simulate install command line to log the changes APT would make, failing if it would remove packages that were
+/// not requested to be removed, unless allowed
+fn simulate_install(
+    install_cmdline: &[String],
+    to_remove: &[String],
+    allow_remove: bool,
+    runner: &dyn runner::CommandRunner,
+) -> Result<(), Error> {
+    info!("Simulating install...");
+    let simulation = simulation::Simulation::run(install_cmdline, runner)?;
+    for kind in &[
+        simulation::ChangeKind::Downgrade,
+        simulation::ChangeKind::Upgrade,
+        simulation::ChangeKind::Install,
+        simulation::ChangeKind::Reinstall,
+        simulation::ChangeKind::Remove,
+    ] {
+        let changes = simulation.changes_of_kind(*kind);
+        if !changes.is_empty() {
+            info!("{} ({}):\n  {}", kind, changes.len(), join(changes, "\n  "));
+        }
+    }
+
+    let unrequested_removals: Vec<String> = simulation
+        .changes_of_kind(simulation::ChangeKind::Remove)
+        .iter()
+        .filter(|c| !to_remove.contains(&c.name))
+        .map(|c| c.to_string())
+        .collect();
+    if !unrequested_removals.is_empty() {
+        if !allow_remove {
+            return Err(Error::Removal {
+                packages: unrequested_removals,
+            });
+        }
+        warn!(
+            "APT will remove {} installed package(s)",
+            unrequested_removals.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Display a summary of the changes to make, and ask for confirmation
+fn confirm_install(
+    packages: &[apt::Package],
+    to_remove: &[String],
+    dpkg_status: &dpkg::DpkgStatus,
+    apt_env: &apt::AptEnv,
+) -> Result<(), Error> {
+    if !atty::is(atty::Stream::Stdin) || !atty::is(atty::Stream::Stderr) {
+        return Err(Error::Other(
+            "Not running in an interactive terminal, use --yes to install without confirmation"
+                .to_string(),
+        ));
+    }
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for package in packages {
+        let installed_package = apt::get_installed_version(&package.name, dpkg_status, apt_env);
+        let direction = match &installed_package {
+            None => "install",
+            Some(installed_package) if installed_package.version > package.version => "downgrade",
+            Some(installed_package) if installed_package.version < package.version => "upgrade",
+            Some(_) => "reinstall",
+        };
+        let size = package
+            .filepath
+            .as_ref()
+            .and_then(|f| fs::metadata(f).ok())
+            .map_or_else(|| "?".to_string(), |m| cache::format_size(m.len()));
+        let source = match (&package.filepath, &package.url) {
+            (Some(filepath), _) if filepath.starts_with(&apt_env.cache_dir) => {
+                "APT cache".to_string()
+            }
+            (_, Some(url)) => reqwest::Url::parse(url)
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_string))
+                .unwrap_or_else(|| url.to_string()),
+            (Some(filepath), None) => filepath.to_string(),
+            (None, None) => "unknown".to_string(),
+        };
+        rows.push(vec![
+            package.name.clone(),
+            installed_package.map_or_else(|| "-".to_string(), |p| p.version.to_string()),
+            package.version.to_string(),
+            direction.to_string(),
+            size,
+            source,
+        ]);
+    }
+    for package_name in to_remove {
+        let installed_package = apt::get_installed_version(package_name, dpkg_status, apt_env);
+        rows.push(vec![
+            package_name.clone(),
+            installed_package.map_or_else(|| "-".to_string(), |p| p.version.to_string()),
+            "-".to_string(),
+            "remove".to_string(),
+            "-".to_string(),
+            "-".to_string(),
+        ]);
+    }
+    // Standard output is reserved for the plan in machine readable formats
+    for line in format_table(
+        &[
+            "PACKAGE",
+            "CURRENT",
+            "TARGET",
+            "DIRECTION",
+            "SIZE",
+            "SOURCE",
+        ],
+        &rows,
+    ) {
+        eprintln!("{}", line);
+    }
+
+    if !Confirm::new()
+        .with_prompt(format!("Install {} package(s)?", packages.len()))
+        .interact()?
+    {
+        return Err(Error::Other("Aborted".to_string()));
+    }
+    Ok(())
+}
+
+/// Run action from command line arguments
+fn run(cl_args: CLArgs) -> Result<(), Error> {
+    // Get global apt env
+    let mut apt_env = apt::read_apt_env(&runner::SystemRunner, cl_args.root.as_deref())?;
+
+    // Read config
+    let config = config::Config::load()?;
+
+    if !cl_args.mirrors.is_empty() {
+        apt_env.mirrors = cl_args.mirrors;
+    } else if !config.mirrors.is_empty() {
+        apt_env.mirrors = config
+            .mirrors
+            .iter()
+            .map(|m| apt::normalize_mirror_url(m))
+            .collect();
+    }
+    debug!("Mirrors: {}", join(&apt_env.mirrors, ", "));
+
+    apt_env.install_recommends = cl_args.with_recommends;
+    apt_env.install_suggests = cl_args.with_suggests;
+
+    if let Some(admin_dir) = cl_args.admin_dir {
+        apt_env.dpkg_status = admin_dir.join("status");
+    }
+    let dpkg_status = dpkg::DpkgStatus::load(&apt_env.dpkg_status)?;
+
+    let mut deb_dirs = config.deb_dirs;
+    deb_dirs.extend(cl_args.deb_dirs);
+    let metadata_cache = match apt::get_download_cache_dir() {
+        Ok(cache_dir) => metadata::MetadataCache::load(
+            &cache_dir.join("metadata.json"),
+            config.metadata_cache_ttl,
+            cl_args.refresh,
+        ),
+        Err(e) => {
+            warn!("Metadata will not be cached: {}", e);
+            metadata::MetadataCache::new(config.metadata_cache_ttl, cl_args.refresh)
+        }
+    };
+    let http_client = http::HttpClient::new(&apt_env.network)?;
+    let mut sources = CandidateSources::new(
+        cl_args.offline,
+        http_client,
+        Box::new(runner::SystemRunner),
+        dpkg_status,
+        metadata_cache,
+        deb_dirs,
+    );
+
+    match cl_args.action {
+        Action::Downgrade {
+            package_specs,
+            dry_run,
+            explain,
+            output_format,
+            repo_dir,
+            allow_remove,
+            yes,
+        } => {
+            // Resolve packages to install
+            let (to_install, to_remove) = resolve_package_specs(
+                &package_specs,
+                cl_args.source,
+                cl_args.broken_rdeps,
+                &mut sources,
+                &apt_env,
+            )?;
+
+            // Output plan, standard output is reserved for it in machine readable formats, and APT would write to
+            // it when installing, so only display the plan then
+            let stdout_reserved = output_format != plan::OutputFormat::Text;
+            if stdout_reserved && !dry_run {
+                info!("Plan is written to standard output, not installing anything");
+            }
+            let dry_run = dry_run || stdout_reserved;
+            if explain {
+                if stdout_reserved {
+                    eprint!("{}", plan::explain(&to_install));
+                } else {
+                    print!("{}", plan::explain(&to_install));
+                }
+            }
+            if stdout_reserved {
+                write_plan(
+                    &mut std::io::stdout(),
+                    &to_install,
+                    &to_remove,
+                    output_format,
+                    &apt_env,
+                )?;
+            }
+
+            // Install
+            if to_install.is_empty() {
+                return Err(Error::NothingToDo);
+            } else {
+                let packages: Vec<apt::Package> =
+                    to_install.into_iter().map(|p| p.package).collect();
+                if let Some(repo_dir) = repo_dir {
+                    if !to_remove.is_empty() {
+                        warn!("Packages to remove: {}", join(&to_remove, ", "));
+                    }
+                    return write_repo(
+                        &packages,
+                        &repo_dir,
+                        stdout_reserved,
+                        sources.runner.as_ref(),
+                    );
+                }
+                let mut install_cmdline =
+                    apt::build_install_cmdline(packages.clone(), &to_remove, &apt_env);
+                simulate_install(
+                    &install_cmdline,
+                    &to_remove,
+                    allow_remove,
+                    sources.runner.as_ref(),
+                )?;
+                if dry_run {
+                    info!("Run:\n{}", join(install_cmdline, " "));
+                } else {
+                    if !yes {
+                        confirm_install(&packages, &to_remove, &sources.dpkg_status, &apt_env)?;
+                    }
+                    apt::set_assume_yes(&mut install_cmdline);
+                    apt::run_install_cmdline(&install_cmdline, sources.runner.as_ref())?;
+                }
+            }
+        }
+        Action::Plan { package_specs, out } => {
+            let (to_install, to_remove) = resolve_package_specs(
+                &package_specs,
+                cl_args.source,
+                cl_args.broken_rdeps,
+                &mut sources,
+                &apt_env,
+            )?;
+            if to_install.is_empty() {
+                return Err(Error::NothingToDo);
+            }
+
+            let lock_file = lock::LockFile::new(
+                &to_install,
+                &to_remove,
+                sources.runner.as_ref(),
+                &sources.dpkg_status,
+                &apt_env,
+            )?;
+            let toml = lock_file.to_toml()?;
+            match out {
+                Some(out) => {
+                    fs::write(&out, toml)?;
+                    info!(
+                        "Wrote plan for {} package(s) to {:?}",
+                        lock_file.packages.len(),
+                        out
+                    );
+                }
+                None => print!("{}", toml),
+            }
+        }
+        Action::Apply {
+            plan_filepath,
+            dry_run,
+            repo_dir,
+            allow_remove,
+            yes,
+        } => {
+            let lock_file = lock::LockFile::load(&plan_filepath)?;
+            let to_remove = lock_file.remove_names();
+
+            // Check system is still in the state the plan was made for
+            let mismatches = lock_file.check_preconditions(&sources.dpkg_status, &apt_env);
+            if !mismatches.is_empty() {
+                return Err(Error::Precondition { mismatches });
+            }
+            if lock_file.packages.is_empty() {
+                return Err(Error::NothingToDo);
+            }
+
+            // Fetch & install
+            let fetch_result = lock_file.fetch(
+                sources.offline,
+                &sources.http_client,
+                &mut sources.metadata_cache,
+                &apt_env,
+            );
+            sources.save();
+            let packages = fetch_result?;
+            if let Some(repo_dir) = repo_dir {
+                if !to_remove.is_empty() {
+                    warn!("Packages to remove: {}", join(&to_remove, ", "));
+                }
+                return write_repo(&packages, &repo_dir, false, sources.runner.as_ref());
+            }
+            let mut install_cmdline =
+                apt::build_install_cmdline(packages.clone(), &to_remove, &apt_env);
+            simulate_install(
+                &install_cmdline,
+                &to_remove,
+                allow_remove,
+                sources.runner.as_ref(),
+            )?;
+            if dry_run {
+                info!("Run:\n{}", join(install_cmdline, " "));
+            } else {
+                if !yes {
+                    confirm_install(&packages, &to_remove, &sources.dpkg_status, &apt_env)?;
+                }
+                apt::set_assume_yes(&mut install_cmdline);
+                apt::run_install_cmdline(&install_cmdline, sources.runner.as_ref())?;
+            }
+        }
+        Action::Changelog { package_spec } => {
+            let result = show_changelog(&package_spec, &mut sources, &apt_env);
+            sources.save();
+            result?;
+        }
+        Action::List { package_name } => {
+            let result = list_package_versions(&package_name, &mut sources, &apt_env);
+            sources.save();
+            result?;
+        }
+        Action::Cache(cache_action) => {
+            manage_cache(
+                &cache_action,
+                sources.runner.as_ref(),
+                &mut sources.metadata_cache,
+                &apt_env,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    // Parse args
+    let cl_args = parse_cl_args();
+
+    match run(cl_args) {
+        Ok(()) => {}
+        Err(Error::NothingToDo) => {
+            info!("Nothing to do");
+            std::process::exit(error::EXIT_NOTHING_TO_DO);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::runner::ReplayRunner;
+
+    /// Build candidate sources from installed packages and recorded command outputs
+    fn build_sources(offline: bool, dpkg_status: &str, runner: ReplayRunner) -> CandidateSources {
+        CandidateSources::new(
+            offline,
+            http::HttpClient::new(&http::NetworkConfig::default()).unwrap(),
+            Box::new(runner),
+            dpkg::DpkgStatus::parse(dpkg_status).unwrap(),
+            metadata::MetadataCache::new(metadata::DEFAULT_TTL, false),
+            Vec::new(),
+        )
+    }
+
+    /// Create empty .deb files in an APT archive cache, and record their control fields
+    fn setup_archive_cache(
+        cache_dir: &Path,
+        packages: &[(&str, &str, &str)],
+    ) -> (apt::AptEnv, ReplayRunner) {
+        let mut apt_env = apt::AptEnv::for_tests();
+        apt_env.cache_dir = format!("{}/", cache_dir.to_str().unwrap());
+        let mut runner = ReplayRunner::default();
+        for (name, version, depends) in packages {
+            let deb_filepath = cache_dir.join(format!("{}_{}_amd64.deb", name, version));
+            fs::write(&deb_filepath, "").unwrap();
+            let mut control = format!(
+                "Package: {}\nVersion: {}\nArchitecture: amd64\n",
+                name, version
+            );
+            if !depends.is_empty() {
+                control.push_str(&format!("Depends: {}\n", depends));
+            }
+            runner = runner.record(
+                &["apt-cache", "show", deb_filepath.to_str().unwrap()],
+                0,
+                &control,
+            );
+        }
+        (apt_env, runner)
+    }
+
+    #[test]
+    fn test_parse_package_specs() {
+        // A package name starting with a digit is not a version of the previous package
+        let package_specs = parse_package_specs(["vim", "0ad"].iter().copied());
+        assert_eq!(package_specs.len(), 2);
+        assert_eq!(package_specs[0].package_name, "vim");
+        assert!(package_specs[0].is_unversioned());
+        assert_eq!(package_specs[1].package_name, "0ad");
+        assert!(package_specs[1].is_unversioned());
+
+        let package_specs = parse_package_specs(["chromium=78.0.3904.108-1"].iter().copied());
+        assert_eq!(package_specs.len(), 1);
+        assert_eq!(package_specs[0].to_string(), "chromium=78.0.3904.108-1");
+    }
+
+    #[test]
+    fn test_format_table() {
+        assert_eq!(
+            format_table(
+                &["PACKAGE", "TARGET"],
+                &[
+                    vec!["libc6".to_string(), "2.36-9".to_string()],
+                    vec!["p1".to_string(), "-".to_string()]
+                ]
+            ),
+            vec!["PACKAGE  TARGET", "libc6    2.36-9", "p1       -"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_packages_offline() {
+        // Only the package to install is in APT archive cache, not its installed dependency
+        let cache_dir = tempfile::tempdir().unwrap();
+        let deb_filepath = cache_dir.path().join("p1_1.0_amd64.deb");
+        fs::write(&deb_filepath, "").unwrap();
+        let mut apt_env = apt::AptEnv::for_tests();
+        apt_env.cache_dir = format!("{}/", cache_dir.path().to_str().unwrap());
+        let runner = ReplayRunner::default().record(
+            &["apt-cache", "show", deb_filepath.to_str().unwrap()],
+            0,
+            "Package: p1\nVersion: 1.0\nArchitecture: amd64\nDepends: p2 (>= 1.5)\n",
+        );
+        let mut sources = build_sources(
+            true,
+            "Package: p1\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.0\n\n\
+             Package: p2\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.0\n",
+            runner,
+        );
+
+        let (to_install, unresolved) =
+            resolve_packages(&["p1=1.0".parse().unwrap()], &mut sources, &apt_env).unwrap();
+        assert!(unresolved.is_empty());
+        assert_eq!(to_install.len(), 1);
+        assert_eq!(to_install[0].package.name, "p1");
+        assert_eq!(to_install[0].package.version.string, "1.0");
+    }
+
+    #[test]
+    fn test_resolve_packages_conflict() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (apt_env, runner) = setup_archive_cache(
+            cache_dir.path(),
+            &[
+                ("p1", "1.0", "p3 (= 1.0)"),
+                ("p2", "1.0", "p3 (= 2.0)"),
+                ("p3", "1.0", ""),
+                ("p3", "2.0", ""),
+            ],
+        );
+        let mut sources = build_sources(true, "", runner);
+
+        match resolve_packages(
+            &["p1=1.0".parse().unwrap(), "p2=1.0".parse().unwrap()],
+            &mut sources,
+            &apt_env,
+        ) {
+            Err(Error::Conflict { package_name, .. }) => assert_eq!(package_name, "p3"),
+            r => panic!(
+                "Unexpected result: {:?}",
+                r.map(|(to_install, _)| to_install.len())
+            ),
         }
     }
+
+    #[test]
+    fn test_resolve_packages_favor_installed() {
+        // Installed version of dependency satisfies it, even if a more recent version is available
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (apt_env, runner) = setup_archive_cache(
+            cache_dir.path(),
+            &[
+                ("p1", "1.0", "p2 (>= 1.0)"),
+                ("p2", "1.5", ""),
+                ("p2", "2.0", ""),
+            ],
+        );
+        let mut sources = build_sources(
+            true,
+            "Package: p1\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.0\n\n\
+             Package: p2\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.5\n",
+            runner,
+        );
+
+        let (to_install, unresolved) =
+            resolve_packages(&["p1=1.0".parse().unwrap()], &mut sources, &apt_env).unwrap();
+        assert!(unresolved.is_empty());
+        assert_eq!(to_install.len(), 1);
+        assert_eq!(to_install[0].package.name, "p1");
+    }
+
+    #[test]
+    fn test_resolve_packages_requested_constraint_on_dependency() {
+        // Constraint requested by user for p2 applies when p2 is reached as a dependency of p1
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (apt_env, runner) = setup_archive_cache(
+            cache_dir.path(),
+            &[
+                ("p1", "1.0", "p2 (>= 1.0)"),
+                ("p2", "1.5", ""),
+                ("p2", "2.0", ""),
+            ],
+        );
+        let mut sources = build_sources(true, "", runner);
+
+        let (to_install, unresolved) = resolve_packages(
+            &["p1=1.0".parse().unwrap(), "p2<<2.0".parse().unwrap()],
+            &mut sources,
+            &apt_env,
+        )
+        .unwrap();
+        assert!(unresolved.is_empty());
+        let mut installed: Vec<(&str, &str)> = to_install
+            .iter()
+            .map(|p| (p.package.name.as_str(), p.package.version.string.as_str()))
+            .collect();
+        installed.sort_unstable();
+        assert_eq!(installed, vec![("p1", "1.0"), ("p2", "1.5")]);
+    }
+
+    #[test]
+    fn test_expand_source_package_specs() {
+        // p2 is architecture dependent, and was rebuilt without source change (binNMU)
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (apt_env, runner) = setup_archive_cache(
+            cache_dir.path(),
+            &[
+                ("p1", "1.0", ""),
+                ("p1", "1.1", ""),
+                ("p2", "1.0+b1", ""),
+                ("p2", "1.0+b2", ""),
+                ("p2", "1.1", ""),
+            ],
+        );
+        let mut sources = build_sources(
+            true,
+            "Package: p1\nStatus: install ok installed\nArchitecture: amd64\nSource: src\nVersion: 2.0\n\n\
+             Package: p2\nStatus: install ok installed\nArchitecture: amd64\nSource: src (2.0)\nVersion: 2.0+b1\n",
+            runner,
+        );
+
+        for package_spec in &["p1=1.0", "src<<1.1", "p2=1.0+b1"] {
+            let expanded_specs = expand_source_package_specs(
+                &[package_spec.parse().unwrap()],
+                &mut sources,
+                &apt_env,
+            )
+            .unwrap();
+            assert_eq!(
+                expanded_specs
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<String>>(),
+                vec!["p1=1.0", "p2=1.0+b2"]
+            );
+        }
+
+        assert!(
+            expand_source_package_specs(&["p1=0.9".parse().unwrap()], &mut sources, &apt_env)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_write_plan() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (apt_env, runner) =
+            setup_archive_cache(cache_dir.path(), &[("p1", "1.0", ""), ("p2", "1.0", "")]);
+        let mut sources = build_sources(true, "", runner);
+        let (to_install, _) = resolve_packages(
+            &["p1=1.0".parse().unwrap(), "p2=1.0".parse().unwrap()],
+            &mut sources,
+            &apt_env,
+        )
+        .unwrap();
+
+        // Output must be a single document
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            &to_install,
+            &[],
+            plan::OutputFormat::Json,
+            &apt_env,
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["packages"].as_array().unwrap().len(), 2);
+        assert!(out.ends_with(b"}\n"));
+
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            &to_install,
+            &[],
+            plan::OutputFormat::Yaml,
+            &apt_env,
+        )
+        .unwrap();
+        let yaml: serde_yaml::Value = serde_yaml::from_slice(&out).unwrap();
+        assert_eq!(yaml["packages"].as_sequence().unwrap().len(), 2);
+        assert!(!out.ends_with(b"\n\n"));
+    }
 }
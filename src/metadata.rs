@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::header::{
+    HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::http::HttpClient;
+
+/// Default time during which cached remote metadata is used without revalidation, in seconds
+pub const DEFAULT_TTL: u64 = 24 * 60 * 60;
+
+/// Parsed values from a remote page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RemoteEntry {
+    /// Unix timestamp of last download or revalidation
+    timestamp: u64,
+
+    /// HTTP ETag header value
+    etag: Option<String>,
+
+    /// HTTP Last-Modified header value
+    last_modified: Option<String>,
+
+    values: Vec<String>,
+}
+
+/// Control data of a local .deb file
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DebEntry {
+    /// File size, used to detect changes
+    size: u64,
+
+    /// File modification Unix timestamp, used to detect changes
+    mtime: u64,
+
+    pub name: String,
+
+    pub version: String,
+
+    pub arch: String,
+}
+
+/// Persistent cache of package metadata
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MetadataCache {
+    /// Filepath the cache is saved to, None to only keep it in memory
+    #[serde(skip)]
+    filepath: Option<PathBuf>,
+
+    /// Time during which remote entries are used without revalidation, in seconds
+    #[serde(skip)]
+    ttl: u64,
+
+    /// Ignore all existing entries
+    #[serde(skip)]
+    refresh: bool,
+
+    /// Remote entries by URL
+    remote: HashMap<String, RemoteEntry>,
+
+    /// Local .deb entries by filepath
+    debs: HashMap<String, DebEntry>,
+
+    /// SHA256 of downloaded .deb files when they were downloaded, by filename
+    #[serde(default)]
+    download_sha256: HashMap<String, String>,
+}
+
+/// Get current Unix timestamp
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Get file size and modification Unix timestamp
+fn get_file_size_mtime(filepath: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(filepath).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((metadata.len(), mtime))
+}
+
+impl MetadataCache {
+    /// Create an empty cache that is not persisted
+    pub fn new(ttl: u64, refresh: bool) -> MetadataCache {
+        MetadataCache {
+            ttl,
+            refresh,
+            ..Default::default()
+        }
+    }
+
+    /// Load cache from file, or create an empty one if it does not exist or can not be read
+    pub fn load(filepath: &Path, ttl: u64, refresh: bool) -> MetadataCache {
+        let mut cache = if filepath.is_file() {
+            match File::open(filepath).map_err(Error::from).and_then(|f| {
+                serde_json::from_reader(BufReader::new(f))
+                    .map_err(|e| Error::parse("metadata cache", &e.to_string()))
+            }) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    warn!("Unable to read metadata cache {:?}: {}", filepath, e);
+                    MetadataCache::default()
+                }
+            }
+        } else {
+            MetadataCache::default()
+        };
+        cache.filepath = Some(filepath.to_path_buf());
+        cache.ttl = ttl;
+        cache.refresh = refresh;
+        cache
+    }
+
+    /// Save cache to its file if any
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(filepath) = &self.filepath {
+            if let Some(parent_dir) = filepath.parent() {
+                fs::create_dir_all(parent_dir)?;
+            }
+            let mut filepath_tmp = filepath.clone().into_os_string();
+            filepath_tmp.push(".tmp");
+            serde_json::to_writer(File::create(&filepath_tmp)?, self).map_err(io::Error::from)?;
+            fs::rename(&filepath_tmp, filepath)?;
+        }
+        Ok(())
+    }
+
+    /// Get values parsed from a remote page, from cache if they are fresh enough, or by downloading and parsing it
+    pub fn get_remote<F>(
+        &mut self,
+        http_client: &HttpClient,
+        url: &str,
+        parse: F,
+    ) -> Result<Vec<String>, Error>
+    where
+        F: FnOnce(&str) -> Result<Vec<String>, Error>,
+    {
+        let entry = if self.refresh {
+            None
+        } else {
+            self.remote.get_mut(url)
+        };
+
+        let mut headers = HeaderMap::new();
+        if let Some(entry) = entry {
+            if now().saturating_sub(entry.timestamp) < self.ttl {
+                trace!("Got {} from metadata cache", url);
+                return Ok(entry.values.clone());
+            }
+            if let Some(etag) = &entry.etag {
+                if let Ok(etag) = HeaderValue::from_str(etag) {
+                    headers.insert(IF_NONE_MATCH, etag);
+                }
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                if let Ok(last_modified) = HeaderValue::from_str(last_modified) {
+                    headers.insert(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+        }
+
+        // Download
+        let response = http_client.get(url, headers)?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(entry) = self.remote.get_mut(url) {
+                trace!("{} not modified", url);
+                entry.timestamp = now();
+                return Ok(entry.values.clone());
+            }
+        }
+        let get_header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = get_header(ETAG);
+        let last_modified = get_header(LAST_MODIFIED);
+        let html = response.text()?;
+
+        // Parse
+        let values = parse(&html)?;
+        self.remote.insert(
+            url.to_string(),
+            RemoteEntry {
+                timestamp: now(),
+                etag,
+                last_modified,
+                values: values.clone(),
+            },
+        );
+
+        Ok(values)
+    }
+
+    /// Get cached control data of a local .deb file, if the file did not change since
+    pub fn get_deb(&self, filepath: &Path) -> Option<DebEntry> {
+        if self.refresh {
+            return None;
+        }
+        let entry = self.debs.get(filepath.to_str()?)?;
+        match get_file_size_mtime(filepath) {
+            Some((size, mtime)) if (size == entry.size) && (mtime == entry.mtime) => {
+                Some(entry.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Store control data of a local .deb file
+    pub fn set_deb(&mut self, filepath: &Path, name: &str, version: &str, arch: &str) {
+        if let (Some(filepath_str), Some((size, mtime))) =
+            (filepath.to_str(), get_file_size_mtime(filepath))
+        {
+            self.debs.insert(
+                filepath_str.to_string(),
+                DebEntry {
+                    size,
+                    mtime,
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    arch: arch.to_string(),
+                },
+            );
+        }
+    }
+
+    /// Get SHA256 of a downloaded .deb file, as it was when downloaded
+    pub fn get_download_sha256(&self, filename: &str) -> Option<&String> {
+        self.download_sha256.get(filename)
+    }
+
+    /// Store SHA256 of a downloaded .deb file
+    pub fn set_download_sha256(&mut self, filename: &str, sha256: &str) {
+        self.download_sha256
+            .insert(filename.to_string(), sha256.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::NetworkConfig;
+
+    #[test]
+    fn test_deb_entries() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let deb_filepath = tmp_dir.path().join("p1.deb");
+        fs::write(&deb_filepath, "content").unwrap();
+        let cache_filepath = tmp_dir.path().join("metadata.json");
+
+        let mut cache = MetadataCache::load(&cache_filepath, DEFAULT_TTL, false);
+        assert!(cache.get_deb(&deb_filepath).is_none());
+        cache.set_deb(&deb_filepath, "p1", "1.0", "amd64");
+        cache.save().unwrap();
+
+        let cache = MetadataCache::load(&cache_filepath, DEFAULT_TTL, false);
+        let entry = cache.get_deb(&deb_filepath).unwrap();
+        assert_eq!(entry.name, "p1");
+        assert_eq!(entry.version, "1.0");
+        assert_eq!(entry.arch, "amd64");
+
+        let cache = MetadataCache::load(&cache_filepath, DEFAULT_TTL, true);
+        assert!(cache.get_deb(&deb_filepath).is_none());
+
+        fs::write(&deb_filepath, "new content").unwrap();
+        let cache = MetadataCache::load(&cache_filepath, DEFAULT_TTL, false);
+        assert!(cache.get_deb(&deb_filepath).is_none());
+    }
+
+    #[test]
+    fn test_remote_entries() {
+        let http_client = HttpClient::new(&NetworkConfig {
+            retries: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cache = MetadataCache::new(DEFAULT_TTL, false);
+        cache.remote.insert(
+            "http://127.0.0.1:1/".to_string(),
+            RemoteEntry {
+                timestamp: now(),
+                etag: None,
+                last_modified: None,
+                values: vec!["v1".to_string()],
+            },
+        );
+
+        // Fresh entry, no network access
+        assert_eq!(
+            cache
+                .get_remote(&http_client, "http://127.0.0.1:1/", |_| unreachable!())
+                .unwrap(),
+            vec!["v1".to_string()]
+        );
+
+        // Expired entry needs revalidation
+        cache
+            .remote
+            .get_mut("http://127.0.0.1:1/")
+            .unwrap()
+            .timestamp = now() - DEFAULT_TTL - 1;
+        assert!(cache
+            .get_remote(&http_client, "http://127.0.0.1:1/", |_| unreachable!())
+            .is_err());
+    }
+}
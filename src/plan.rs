@@ -0,0 +1,399 @@
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+use simple_error::SimpleError;
+
+use crate::apt;
+use crate::error::Error;
+
+/// Version of the plan output schema, to increment on incompatible changes
+const SCHEMA_VERSION: u32 = 1;
+
+/// A package selected for installation, and the reason it was selected
+#[derive(Clone, Debug)]
+pub struct PlannedPackage {
+    pub package: apt::Package,
+
+    pub installed_package: Option<apt::Package>,
+
+    /// Package that depends on this one, None if it was requested by user
+    pub parent: Option<apt::Package>,
+
+    /// Dependency that caused the package to be selected
+    pub dependency: apt::PackageDependency,
+
+    /// All packages depending on this one with their dependency, parent is None if requested by user
+    pub required_by: Vec<(Option<apt::Package>, apt::PackageDependency)>,
+}
+
+impl PlannedPackage {
+    /// Get dependencies that the installed version does not satisfy
+    pub fn installed_ruled_out_by(&self) -> Vec<&(Option<apt::Package>, apt::PackageDependency)> {
+        match &self.installed_package {
+            Some(installed_package) => self
+                .required_by
+                .iter()
+                .filter(|(_, d)| !d.is_satisfied_by(installed_package))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Plan output format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(SimpleError::new(format!("Unknown output format {:?}", s))),
+        }
+    }
+}
+
+/// Reason a package is part of the plan, or reason the installed version was ruled out
+#[derive(Debug, Serialize)]
+pub struct PlanReason {
+    /// Name of the package that depends on this one, null if requested by user
+    pub parent: Option<String>,
+
+    /// Version of the package that depends on this one, null if requested by user
+    pub parent_version: Option<String>,
+
+    /// Dependency that caused the package to be selected
+    pub dependency: String,
+}
+
+/// Plan entry for a single package
+#[derive(Debug, Serialize)]
+pub struct PlanPackage {
+    pub name: String,
+
+    /// Currently installed version, null if not installed
+    pub old_version: Option<String>,
+
+    pub new_version: String,
+
+    pub arch: Option<String>,
+
+    pub filepath: Option<String>,
+
+    pub url: Option<String>,
+
+    /// SHA256 of the package file, null if the file is not available locally
+    pub sha256: Option<String>,
+
+    pub reason: PlanReason,
+
+    /// Dependencies that the currently installed version does not satisfy
+    pub installed_ruled_out_by: Vec<PlanReason>,
+}
+
+impl PlanReason {
+    fn new(parent: &Option<apt::Package>, dependency: &apt::PackageDependency) -> PlanReason {
+        PlanReason {
+            parent: parent.as_ref().map(|p| p.name.clone()),
+            parent_version: parent.as_ref().map(|p| p.version.to_string()),
+            dependency: dependency.to_string(),
+        }
+    }
+}
+
+/// Resolved install plan
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub schema_version: u32,
+
+    pub packages: Vec<PlanPackage>,
+
+    /// Names of installed packages to remove, because their dependencies would be broken
+    pub remove: Vec<String>,
+
+    /// Command line to run to apply the plan, empty if there is nothing to do
+    pub command: Vec<String>,
+}
+
+impl Plan {
+    /// Build plan from resolved packages and packages to remove
+    pub fn new(
+        planned_packages: &[PlannedPackage],
+        to_remove: &[String],
+        apt_env: &apt::AptEnv,
+    ) -> Result<Plan, Error> {
+        let mut packages = Vec::new();
+        for planned_package in planned_packages {
+            let package = &planned_package.package;
+            let sha256 = match &package.filepath {
+                Some(filepath) if Path::new(filepath).is_file() => {
+                    Some(apt::get_file_sha256(Path::new(filepath))?)
+                }
+                _ => None,
+            };
+            packages.push(PlanPackage {
+                name: package.name.clone(),
+                old_version: planned_package
+                    .installed_package
+                    .as_ref()
+                    .map(|p| p.version.to_string()),
+                new_version: package.version.to_string(),
+                arch: package.arch.clone(),
+                filepath: package.filepath.clone(),
+                url: package.url.clone(),
+                sha256,
+                reason: PlanReason::new(&planned_package.parent, &planned_package.dependency),
+                installed_ruled_out_by: planned_package
+                    .installed_ruled_out_by()
+                    .into_iter()
+                    .map(|(parent, dependency)| PlanReason::new(parent, dependency))
+                    .collect(),
+            });
+        }
+
+        let command = if planned_packages.is_empty() {
+            Vec::new()
+        } else {
+            apt::build_install_cmdline(
+                planned_packages.iter().map(|p| p.package.clone()).collect(),
+                to_remove,
+                apt_env,
+            )
+        };
+
+        Ok(Plan {
+            schema_version: SCHEMA_VERSION,
+            packages,
+            remove: to_remove.to_vec(),
+            command,
+        })
+    }
+
+    /// Serialize plan
+    pub fn write<W: io::Write>(&self, mut writer: W, format: OutputFormat) -> Result<(), Error> {
+        // Serialize first, so that write errors are not reported as serialization ones
+        let content = match format {
+            OutputFormat::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            OutputFormat::Yaml => serde_yaml::to_string(self).map_err(|e| e.to_string()),
+            OutputFormat::Text => {
+                return Err(Error::Other(
+                    "Text format is not a serialization format".to_string(),
+                ));
+            }
+        }
+        .map_err(|e| Error::Other(format!("Unable to serialize plan: {}", e)))?;
+        writer.write_all(content.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Describe a dependency edge for humans
+pub fn describe_edge(parent: &Option<apt::Package>, dependency: &apt::PackageDependency) -> String {
+    match parent {
+        Some(parent) => format!(
+            "{} {} depends on {}",
+            parent.name, parent.version, dependency
+        ),
+        None => format!("requested {}", dependency),
+    }
+}
+
+/// Write planned packages subtree, and their dependents recursively
+fn explain_subtree(
+    out: &mut String,
+    planned_packages: &[PlannedPackage],
+    planned_package: &PlannedPackage,
+    depth: usize,
+) {
+    let indent = "  ".repeat(depth);
+    let package = &planned_package.package;
+    let installed = match &planned_package.installed_package {
+        Some(installed_package) => format!("installed: {}", installed_package.version),
+        None => "not installed".to_string(),
+    };
+    writeln!(
+        out,
+        "{}{} {} ({}), {}",
+        indent,
+        package.name,
+        package.version,
+        installed,
+        describe_edge(&planned_package.parent, &planned_package.dependency)
+    )
+    .unwrap();
+    if planned_package.installed_package.is_some() {
+        let ruled_out_by = planned_package.installed_ruled_out_by();
+        if ruled_out_by.is_empty() {
+            writeln!(
+                out,
+                "{}  installed version is compatible, but the most recent matching version was preferred",
+                indent
+            )
+            .unwrap();
+        } else {
+            for (parent, dependency) in ruled_out_by {
+                writeln!(
+                    out,
+                    "{}  installed version ruled out: {}",
+                    indent,
+                    describe_edge(parent, dependency)
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    for child in planned_packages.iter().filter(|p| match &p.parent {
+        Some(parent) => parent == package,
+        None => false,
+    }) {
+        explain_subtree(out, planned_packages, child, depth + 1);
+    }
+}
+
+/// Build dependency tree of planned packages, from requested packages down to each dependency
+pub fn explain(planned_packages: &[PlannedPackage]) -> String {
+    let mut out = String::new();
+    for root in planned_packages.iter().filter(|p| p.parent.is_none()) {
+        explain_subtree(&mut out, planned_packages, root, 0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_planned_packages() -> Vec<PlannedPackage> {
+        let requested_package = apt::Package {
+            name: "p1".to_string(),
+            version: apt::PackageVersion {
+                string: "1.0.1".to_string(),
+            },
+            arch: Some("amd64".to_string()),
+            filepath: Some("/nonexistent/p1_1.0.1_amd64.deb".to_string()),
+            url: None,
+        };
+        vec![
+            PlannedPackage {
+                package: requested_package.clone(),
+                installed_package: Some(apt::Package {
+                    name: "p1".to_string(),
+                    version: apt::PackageVersion {
+                        string: "1.0.2".to_string(),
+                    },
+                    arch: Some("amd64".to_string()),
+                    filepath: None,
+                    url: None,
+                }),
+                parent: None,
+                dependency: "p1=1.0.1".parse().unwrap(),
+                required_by: vec![
+                    (None, "p1=1.0.1".parse().unwrap()),
+                    (Some(requested_package.clone()), "p1>=1.0".parse().unwrap()),
+                ],
+            },
+            PlannedPackage {
+                package: apt::Package {
+                    name: "p2".to_string(),
+                    version: apt::PackageVersion {
+                        string: "2.0".to_string(),
+                    },
+                    arch: Some("all".to_string()),
+                    filepath: Some("/nonexistent/p2_2.0_all.deb".to_string()),
+                    url: Some("http://example.com/p2_2.0_all.deb".to_string()),
+                },
+                installed_package: None,
+                parent: Some(requested_package.clone()),
+                dependency: "p2<<3".parse().unwrap(),
+                required_by: vec![(Some(requested_package), "p2<<3".parse().unwrap())],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_plan_json() {
+        let planned_packages = get_planned_packages();
+        let plan = Plan::new(
+            &planned_packages,
+            &["p3".to_string()],
+            &apt::AptEnv::for_tests(),
+        )
+        .unwrap();
+        let mut buffer = Vec::new();
+        plan.write(&mut buffer, OutputFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["packages"][0]["name"], "p1");
+        assert_eq!(json["remove"][0], "p3");
+        assert_eq!(json["packages"][0]["old_version"], "1.0.2");
+        assert_eq!(json["packages"][0]["new_version"], "1.0.1");
+        assert_eq!(json["packages"][0]["sha256"], serde_json::Value::Null);
+        assert_eq!(
+            json["packages"][0]["reason"]["parent"],
+            serde_json::Value::Null
+        );
+        assert_eq!(json["packages"][0]["reason"]["dependency"], "p1=1.0.1");
+        assert_eq!(
+            json["packages"][0]["installed_ruled_out_by"][0]["dependency"],
+            "p1=1.0.1"
+        );
+        assert_eq!(
+            json["packages"][0]["installed_ruled_out_by"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(json["packages"][1]["old_version"], serde_json::Value::Null);
+        assert_eq!(
+            json["packages"][1]["url"],
+            "http://example.com/p2_2.0_all.deb"
+        );
+        assert_eq!(json["packages"][1]["reason"]["parent"], "p1");
+        assert_eq!(json["packages"][1]["reason"]["parent_version"], "1.0.1");
+        assert_eq!(json["packages"][1]["reason"]["dependency"], "p2<<3");
+        assert_eq!(json["command"][0], "apt-get");
+        assert_eq!(json["command"][5], "/nonexistent/p2_2.0_all.deb");
+        assert_eq!(json["command"][6], "p3-");
+
+        // Output closed early, ie. piped to 'head'
+        struct BrokenPipe;
+        impl io::Write for BrokenPipe {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        match plan.write(BrokenPipe, OutputFormat::Json) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::BrokenPipe),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let planned_packages = get_planned_packages();
+        assert_eq!(
+            explain(&planned_packages),
+            "p1 1.0.1 (installed: 1.0.2), requested p1=1.0.1
+  installed version ruled out: requested p1=1.0.1
+  p2 2.0 (not installed), p1 1.0.1 depends on p2<<3
+"
+        );
+    }
+}
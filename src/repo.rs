@@ -0,0 +1,225 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::apt;
+use crate::error::Error;
+use crate::runner::CommandRunner;
+
+/// Origin & label of generated repositories
+const REPO_ORIGIN: &str = "apt-downgrade";
+
+/// Format a date like RFC 2822, in UTC, ie. 'Sat, 01 Feb 2020 12:30:00 UTC'
+fn format_release_date(date: SystemTime) -> String {
+    let secs = date
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+
+    // Convert days since epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} UTC",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60
+    )
+}
+
+/// Build Release file content for a flat repository
+fn build_release(packages_index: &str, date: SystemTime) -> String {
+    format!(
+        "Origin: {}\nLabel: {}\nDate: {}\nSHA256:\n {:x} {} Packages\n",
+        REPO_ORIGIN,
+        REPO_ORIGIN,
+        format_release_date(date),
+        Sha256::digest(packages_index.as_bytes()),
+        packages_index.len()
+    )
+}
+
+/// Copy package files to a directory, and generate Packages & Release index files, to use it as a flat APT
+/// repository
+pub fn write_flat_repo(
+    packages: &[apt::Package],
+    dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+
+    let mut packages_index = String::new();
+    for package in packages {
+        let filepath = package.filepath.as_ref().ok_or_else(|| {
+            Error::Other(format!("No file for {} {}", package.name, package.version))
+        })?;
+
+        // Get full control record, with the exact version
+        let control = runner.run_checked("dpkg-deb", &["--field", filepath])?;
+        let get_field = |name| {
+            apt::get_control_field(&control, name).ok_or_else(|| {
+                Error::parse(
+                    &format!("dpkg-deb output for {:?}", filepath),
+                    &format!("missing {} field", name),
+                )
+            })
+        };
+        let filename = format!(
+            "{}_{}_{}.deb",
+            get_field("Package")?,
+            get_field("Version")?.replace(':', "%3a"),
+            get_field("Architecture")?
+        );
+
+        let repo_filepath = dir.join(&filename);
+        debug!("Copying {:?} to {:?}", filepath, repo_filepath);
+        fs::copy(filepath, &repo_filepath)?;
+
+        packages_index.push_str(control.trim_end());
+        packages_index.push_str(&format!(
+            "\nFilename: ./{}\nSize: {}\nSHA256: {}\n\n",
+            filename,
+            fs::metadata(&repo_filepath)?.len(),
+            apt::get_file_sha256(&repo_filepath)?
+        ));
+    }
+
+    fs::write(dir.join("Packages"), &packages_index)?;
+    fs::write(
+        dir.join("Release"),
+        build_release(&packages_index, SystemTime::now()),
+    )?;
+
+    Ok(())
+}
+
+/// Get sources.list lines to use a flat repository directory, on this machine, and on other ones once the
+/// directory is served over HTTP, with a URI template to replace
+pub fn get_sources_list_lines(dir: &Path) -> Result<(String, String), Error> {
+    Ok((
+        format!(
+            "deb [trusted=yes] file:{} ./",
+            dir.canonicalize()?.to_string_lossy()
+        ),
+        "deb [trusted=yes] http://<host>/<path>/ ./".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::runner::ReplayRunner;
+
+    #[test]
+    fn test_build_release() {
+        assert_eq!(
+            build_release("", UNIX_EPOCH + Duration::from_secs(1_580_560_200)),
+            "Origin: apt-downgrade
+Label: apt-downgrade
+Date: Sat, 01 Feb 2020 12:30:00 UTC
+SHA256:
+ e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 0 Packages
+"
+        );
+    }
+
+    #[test]
+    fn test_format_release_date() {
+        for (secs, date) in &[
+            (0, "Thu, 01 Jan 1970 00:00:00 UTC"),
+            (951_782_400, "Tue, 29 Feb 2000 00:00:00 UTC"),
+            (1_580_560_200, "Sat, 01 Feb 2020 12:30:00 UTC"),
+            (1_735_689_599, "Tue, 31 Dec 2024 23:59:59 UTC"),
+        ] {
+            assert_eq!(
+                format_release_date(UNIX_EPOCH + Duration::from_secs(*secs)),
+                *date
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_flat_repo() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let deb_filepath = tmp_dir.path().join("p1.deb");
+        fs::write(&deb_filepath, "p1 package content").unwrap();
+        let runner = ReplayRunner::default().record(
+            &["dpkg-deb", "--field", deb_filepath.to_str().unwrap()],
+            0,
+            "Package: p1\nVersion: 1:1.0-1\nArchitecture: all\nMaintainer: Test <test@example.com>\nDepends: p2 (>= 2.0)\nDescription: Test\n long description\n",
+        );
+
+        let repo_dir = tmp_dir.path().join("repo");
+        let packages = vec![apt::Package {
+            name: "p1".to_string(),
+            version: apt::PackageVersion {
+                string: "1.0-1".to_string(),
+            },
+            arch: Some("all".to_string()),
+            filepath: Some(deb_filepath.to_str().unwrap().to_string()),
+            url: None,
+        }];
+        write_flat_repo(&packages, &repo_dir, &runner).unwrap();
+
+        let repo_filepath = repo_dir.join("p1_1%3a1.0-1_all.deb");
+        assert!(repo_filepath.is_file());
+        let packages_index = fs::read_to_string(repo_dir.join("Packages")).unwrap();
+        assert_eq!(
+            packages_index,
+            format!(
+                "Package: p1
+Version: 1:1.0-1
+Architecture: all
+Maintainer: Test <test@example.com>
+Depends: p2 (>= 2.0)
+Description: Test
+ long description
+Filename: ./p1_1%3a1.0-1_all.deb
+Size: {}
+SHA256: {}
+
+",
+                fs::metadata(&repo_filepath).unwrap().len(),
+                apt::get_file_sha256(&repo_filepath).unwrap()
+            )
+        );
+        let release = fs::read_to_string(repo_dir.join("Release")).unwrap();
+        assert!(release.contains(&format!(
+            "\n {:x} {} Packages\n",
+            Sha256::digest(packages_index.as_bytes()),
+            packages_index.len()
+        )));
+
+        assert_eq!(
+            get_sources_list_lines(&repo_dir).unwrap(),
+            (
+                format!(
+                    "deb [trusted=yes] file:{} ./",
+                    repo_dir.canonicalize().unwrap().to_str().unwrap()
+                ),
+                "deb [trusted=yes] http://<host>/<path>/ ./".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,247 @@
+#[cfg(test)]
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+#[cfg(test)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::error::{CommandError, Error};
+
+/// Captured result of an external command
+pub struct CommandOutput {
+    pub status: ExitStatus,
+
+    pub stdout: String,
+
+    pub stderr: String,
+}
+
+/// Runs external tools (apt-cache, apt-config, dpkg-deb...)
+pub trait CommandRunner {
+    /// Run a command and capture its standard output, only fails if the command could not be run at all
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Run a command with standard streams attached to the terminal, for interactive commands
+    fn run_attached(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus>;
+
+    /// Run a command and process its standard output as a stream, for large or binary output, fails if the
+    /// command returns non zero code
+    fn run_streamed(
+        &self,
+        program: &str,
+        args: &[&str],
+        process: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error>;
+
+    /// Run a command and get its standard output, fails if the command returns non zero code
+    fn run_checked(&self, program: &str, args: &[&str]) -> Result<String, Error> {
+        let output = self.run(program, args)?;
+        check_status(program, args, output.status, &output.stderr)?;
+        Ok(output.stdout)
+    }
+}
+
+/// Fail if a command returned non zero code
+pub fn check_status(
+    program: &str,
+    args: &[&str],
+    status: ExitStatus,
+    output: &str,
+) -> Result<(), Error> {
+    if !status.success() {
+        let mut cmd: Vec<String> = vec![program.to_string()];
+        cmd.extend(args.iter().map(|s| (*s).to_string()));
+        return Err(Error::Command(CommandError {
+            status,
+            cmd,
+            output: output.to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// Check if a program can be found in PATH, to skip tests that need tools only available on Debian based systems
+#[cfg(test)]
+pub fn is_in_path(program: &str) -> bool {
+    match std::env::var_os("PATH") {
+        Some(paths) => std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()),
+        None => false,
+    }
+}
+
+/// Runner for the real system commands
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let output = Command::new(program).args(args).env("LANG", "C").output()?;
+        Ok(CommandOutput {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    fn run_attached(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Command::new(program).args(args).status()
+    }
+
+    fn run_streamed(
+        &self,
+        program: &str,
+        args: &[&str],
+        process: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut child = Command::new(program)
+            .args(args)
+            .env("LANG", "C")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut stdout = child.stdout.take().unwrap();
+        let result = process(&mut stdout);
+        // Read what is left so the command does not fail writing to a closed pipe
+        let drained = io::copy(&mut stdout, &mut io::sink());
+        let status = child.wait()?;
+        check_status(program, args, status, "")?;
+        drained?;
+        result
+    }
+}
+
+/// Runner replaying recorded outputs, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct ReplayRunner {
+    /// Exit code, standard output and standard error, by command line
+    outputs: HashMap<Vec<String>, (i32, String, String)>,
+}
+
+#[cfg(test)]
+impl ReplayRunner {
+    /// Record the exit code and standard output to replay for a command line
+    pub fn record(self, cmd: &[&str], code: i32, stdout: &str) -> ReplayRunner {
+        self.record_with_stderr(cmd, code, stdout, "")
+    }
+
+    /// Record the exit code, standard output and standard error to replay for a command line
+    pub fn record_with_stderr(
+        mut self,
+        cmd: &[&str],
+        code: i32,
+        stdout: &str,
+        stderr: &str,
+    ) -> ReplayRunner {
+        self.outputs.insert(
+            cmd.iter().map(|s| (*s).to_string()).collect(),
+            (code, stdout.to_string(), stderr.to_string()),
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for ReplayRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let mut cmd: Vec<String> = vec![program.to_string()];
+        cmd.extend(args.iter().map(|s| (*s).to_string()));
+        match self.outputs.get(&cmd) {
+            Some((code, stdout, stderr)) => Ok(CommandOutput {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No recorded output for {:?}", cmd),
+            )),
+        }
+    }
+
+    fn run_attached(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        self.run(program, args).map(|o| o.status)
+    }
+
+    fn run_streamed(
+        &self,
+        program: &str,
+        args: &[&str],
+        process: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let output = self.run(program, args)?;
+        check_status(program, args, output.status, &output.stderr)?;
+        process(&mut output.stdout.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_runner() {
+        let runner = ReplayRunner::default()
+            .record(&["apt-cache", "policy", "p1"], 0, "p1:\n")
+            .record_with_stderr(
+                &["apt-cache", "show", "p1"],
+                100,
+                "",
+                "E: No packages found\n",
+            );
+
+        assert_eq!(
+            runner.run_checked("apt-cache", &["policy", "p1"]).unwrap(),
+            "p1:\n"
+        );
+        match runner.run_checked("apt-cache", &["show", "p1"]) {
+            Err(Error::Command(e)) => {
+                assert_eq!(e.status.code(), Some(100));
+                assert_eq!(e.cmd, vec!["apt-cache", "show", "p1"]);
+                assert_eq!(
+                    e.to_string(),
+                    "Command apt-cache show p1 returned 100:\nE: No packages found"
+                );
+            }
+            _ => panic!(),
+        }
+        assert!(runner.run("apt-cache", &["show", "p2"]).is_err());
+
+        let mut stdout = String::new();
+        runner
+            .run_streamed("apt-cache", &["policy", "p1"], &mut |r| {
+                r.read_to_string(&mut stdout)?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(stdout, "p1:\n");
+        assert!(runner
+            .run_streamed("apt-cache", &["show", "p1"], &mut |_| Ok(()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_system_runner() {
+        let output = SystemRunner
+            .run("sh", &["-c", "echo $LANG; echo error >&2; exit 3"])
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, "C\n");
+        assert_eq!(output.stderr, "error\n");
+
+        let mut line_count = 0;
+        SystemRunner
+            .run_streamed("seq", &["100000"], &mut |r| {
+                // Only read the beginning of the output
+                let mut buf = [0; 6];
+                r.read_exact(&mut buf)?;
+                line_count = buf.iter().filter(|b| **b == b'\n').count();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(line_count, 3);
+        assert!(SystemRunner
+            .run_streamed("sh", &["-c", "exit 3"], &mut |_| Ok(()))
+            .is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+
+use crate::apt::PackageVersion;
+use crate::error::Error;
+use crate::runner::{check_status, CommandRunner};
+
+/// Kind of change APT would make to a package
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Install,
+    Upgrade,
+    Downgrade,
+    Reinstall,
+    Remove,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChangeKind::Install => "New installs",
+            ChangeKind::Upgrade => "Upgrades",
+            ChangeKind::Downgrade => "Downgrades",
+            ChangeKind::Reinstall => "Reinstalls",
+            ChangeKind::Remove => "Removals",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Change APT would make to a package
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageChange {
+    pub name: String,
+
+    /// Installed version, None for new installs
+    pub old_version: Option<PackageVersion>,
+
+    /// Version to install, None for removals
+    pub new_version: Option<PackageVersion>,
+
+    pub kind: ChangeKind,
+}
+
+impl fmt::Display for PackageChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.old_version, &self.new_version) {
+            (Some(old_version), Some(new_version)) if old_version != new_version => {
+                write!(f, "{} {} -> {}", self.name, old_version, new_version)
+            }
+            (_, Some(version)) | (Some(version), None) => {
+                write!(f, "{} {}", self.name, version)
+            }
+            (None, None) => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Changes APT would make when running a command line, from its simulation
+#[derive(Debug)]
+pub struct Simulation {
+    pub changes: Vec<PackageChange>,
+}
+
+impl Simulation {
+    /// Parse 'Inst' & 'Remv' lines of apt-get simulation output, like
+    /// 'Inst p1 [2.0] (1.0 Debian:12/stable [amd64])' or 'Remv p2 [1.0]'
+    pub fn parse(output: &str) -> Result<Simulation, Error> {
+        let context = "apt-get simulation output";
+        let parse_version = |s: &str| PackageVersion {
+            string: s.rsplit(':').next().unwrap().to_string(),
+        };
+
+        let mut changes = Vec::new();
+        for line in output.lines() {
+            let mut tokens = line.split(' ').peekable();
+            let action = tokens.next().unwrap();
+            if (action != "Inst") && (action != "Remv") {
+                continue;
+            }
+            let name = tokens
+                .next()
+                .ok_or_else(|| Error::parse(context, &format!("unexpected line {:?}", line)))?
+                .to_string();
+            let old_version = match tokens.peek() {
+                Some(t) if t.starts_with('[') && t.ends_with(']') => {
+                    let version = parse_version(&t[1..t.len() - 1]);
+                    tokens.next();
+                    Some(version)
+                }
+                _ => None,
+            };
+
+            let change = if action == "Remv" {
+                PackageChange {
+                    name,
+                    old_version,
+                    new_version: None,
+                    kind: ChangeKind::Remove,
+                }
+            } else {
+                let new_version = tokens
+                    .next()
+                    .filter(|t| t.starts_with('('))
+                    .map(|t| parse_version(&t[1..]))
+                    .ok_or_else(|| {
+                        Error::parse(context, &format!("missing version in line {:?}", line))
+                    })?;
+                let kind = match &old_version {
+                    None => ChangeKind::Install,
+                    Some(old_version) if old_version < &new_version => ChangeKind::Upgrade,
+                    Some(old_version) if old_version > &new_version => ChangeKind::Downgrade,
+                    Some(_) => ChangeKind::Reinstall,
+                };
+                PackageChange {
+                    name,
+                    old_version,
+                    new_version: Some(new_version),
+                    kind,
+                }
+            };
+            changes.push(change);
+        }
+
+        Ok(Simulation { changes })
+    }
+
+    /// Simulate an apt-get command line, without changing anything
+    pub fn run(cmdline: &[String], runner: &dyn CommandRunner) -> Result<Simulation, Error> {
+        let mut args: Vec<&str> = vec!["-s"];
+        args.extend(cmdline[1..].iter().map(String::as_str));
+        let output = runner.run(&cmdline[0], &args)?;
+        // APT explains problems like unmet dependencies on standard output, and sums them up on standard error
+        check_status(
+            &cmdline[0],
+            &args,
+            output.status,
+            &format!("{}{}", output.stdout, output.stderr),
+        )?;
+        Simulation::parse(&output.stdout)
+    }
+
+    /// Get changes of a kind
+    pub fn changes_of_kind(&self, kind: ChangeKind) -> Vec<&PackageChange> {
+        self.changes.iter().filter(|c| c.kind == kind).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::runner::ReplayRunner;
+
+    #[test]
+    fn test_simulation() {
+        let simulation =
+            Simulation::parse(include_str!("../test_data/apt-get/simulate.txt")).unwrap();
+        assert_eq!(
+            simulation
+                .changes
+                .iter()
+                .map(|c| (c.kind, c.to_string()))
+                .collect::<Vec<(ChangeKind, String)>>(),
+            vec![
+                (
+                    ChangeKind::Upgrade,
+                    "base-passwd 3.6.1 -> 3.7.0".to_string()
+                ),
+                (ChangeKind::Remove, "curl 7.88.1-10+deb12u14".to_string()),
+                (
+                    ChangeKind::Downgrade,
+                    "bzip2-doc 1.0.8-5 -> 1.0.7-1".to_string()
+                ),
+                (ChangeKind::Install, "newpkg 1.0".to_string()),
+            ]
+        );
+        assert_eq!(
+            simulation.changes_of_kind(ChangeKind::Remove)[0].name,
+            "curl"
+        );
+
+        // Epochs and reinstalls
+        let simulation = Simulation::parse(
+            "Inst vim-common [2:9.0.1378-2] (2:9.0.1378-2 Debian:12/stable [all])\n",
+        )
+        .unwrap();
+        assert_eq!(simulation.changes[0].kind, ChangeKind::Reinstall);
+        assert_eq!(
+            simulation.changes[0].new_version,
+            Some(PackageVersion {
+                string: "9.0.1378-2".to_string()
+            })
+        );
+
+        assert!(Simulation::parse("Inst p1 [1.0]\n").is_err());
+        assert!(Simulation::parse("").unwrap().changes.is_empty());
+
+        let runner = ReplayRunner::default().record(
+            &["apt-get", "-s", "install", "/p1.deb", "p2-"],
+            0,
+            "Inst p1 [2.0] (1.0 local-deb [all])\nRemv p2 [1.0]\nConf p1 (1.0 local-deb [all])\n",
+        );
+        let cmdline: Vec<String> = ["apt-get", "install", "/p1.deb", "p2-"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let simulation = Simulation::run(&cmdline, &runner).unwrap();
+        assert_eq!(simulation.changes.len(), 2);
+        assert_eq!(simulation.changes[0].kind, ChangeKind::Downgrade);
+
+        // APT explanations are kept on failure
+        let runner = ReplayRunner::default().record_with_stderr(
+            &["apt-get", "-s", "install", "/p1.deb", "p2-"],
+            100,
+            "The following packages have unmet dependencies:\n p1 : Depends: p3 but it is not installable\n",
+            "E: Unable to correct problems, you have held broken packages.\n",
+        );
+        match Simulation::run(&cmdline, &runner) {
+            Err(Error::Command(e)) => assert_eq!(
+                e.to_string(),
+                "Command apt-get -s install /p1.deb p2- returned 100:\n\
+                 The following packages have unmet dependencies:\n \
+                 p1 : Depends: p3 but it is not installable\n\
+                 E: Unable to correct problems, you have held broken packages."
+            ),
+            _ => panic!(),
+        }
+    }
+}